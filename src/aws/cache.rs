@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use chrono::{DateTime, Duration, Utc};
+use openssl::sha::sha256;
+use rusoto_core::Region;
+use rusoto_sts::AssumeRoleRequest;
+
+use crate::aws::credential_source::CredentialSource;
+use crate::aws::credentials::Credentials;
+
+/// Credentials are refreshed when they are this close to expiring
+const REFRESH_MARGIN_SECONDS: i64 = 300;
+
+/// The credentials a role was assumed from, roles assumed from different sources are cached apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheSource {
+    /// The session held in the manager config
+    Manager,
+    Profile(String),
+    CredentialSource(CredentialSource),
+    /// Hex sha256 of the web identity token, the token itself isn't kept
+    WebIdentity(String),
+}
+
+impl CacheSource {
+    pub fn web_identity(web_identity_token: &str) -> CacheSource {
+        let digest = sha256(web_identity_token.as_bytes());
+        CacheSource::WebIdentity(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub role_arn: String,
+    pub region: String,
+    pub role_session_name: String,
+    pub duration_seconds: Option<i64>,
+    pub external_id: Option<String>,
+    pub policy: Option<String>,
+    pub source: CacheSource,
+    /// Workspaces can configure the same role differently, eg with their own `via` chain
+    pub workspace: Option<String>,
+}
+
+impl CacheKey {
+    pub fn new(request: &AssumeRoleRequest, region: &Region, source: CacheSource, workspace: Option<String>) -> CacheKey {
+        CacheKey {
+            role_arn: request.role_arn.clone(),
+            region: region.name().to_owned(),
+            role_session_name: request.role_session_name.clone(),
+            duration_seconds: request.duration_seconds,
+            external_id: request.external_id.clone(),
+            policy: request.policy.clone(),
+            source,
+            workspace,
+        }
+    }
+}
+
+/// In process cache of assumed role credentials and the source credentials they are assumed from, shared
+/// between the warp handlers
+#[derive(Debug, Clone, Default)]
+pub struct CredentialCache {
+    entries: Arc<Mutex<HashMap<CacheKey, Credentials>>>,
+    sources: Arc<Mutex<HashMap<CacheSource, Credentials>>>,
+    /// Held while a source is fetched so requests arriving together, eg one per role, fetch it once
+    fetching: Arc<Mutex<HashMap<CacheSource, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl CredentialCache {
+    pub fn new() -> CredentialCache {
+        CredentialCache::default()
+    }
+
+    /// Returns the cached credentials unless they are missing or about to expire
+    pub fn get(&self, key: &CacheKey) -> Option<Credentials> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(credentials) if !needs_refresh(credentials) => {
+                debug!("Using cached credentials for {}", key.role_arn);
+                Some(credentials.clone())
+            }
+            Some(_) => {
+                debug!("Cached credentials for {} are expiring, refreshing", key.role_arn);
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, credentials: Credentials) {
+        self.entries.lock().unwrap().insert(key, credentials);
+    }
+
+    /// The source credentials, fetched when they aren't cached or are about to expire. Sources without an expiry,
+    /// eg static keys, are fetched every time.
+    pub async fn get_or_fetch_source<F, Fut>(&self, source: CacheSource, fetch: F) -> Result<Credentials, Error>
+        where F: FnOnce() -> Fut,
+              Fut: Future<Output=Result<Credentials, Error>>, {
+        let fetching = self.fetching
+            .lock()
+            .unwrap()
+            .entry(source.clone())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+            .clone();
+        let _fetching = fetching.lock().await;
+
+        if let Some(credentials) = self.sources.lock().unwrap().get(&source).filter(|credentials| !needs_refresh(credentials)) {
+            debug!("Using cached source credentials for {:?}", source);
+            return Ok(credentials.clone());
+        }
+        let credentials = fetch().await?;
+        self.sources.lock().unwrap().insert(source, credentials.clone());
+        Ok(credentials)
+    }
+
+    /// When each of the cached credentials expires, credentials without an expiry are left out
+    pub fn expirations(&self) -> Vec<(CacheKey, DateTime<Utc>)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, credentials)| credentials.expiration.map(|expiration| (key.clone(), expiration)))
+            .collect()
+    }
+}
+
+fn needs_refresh(credentials: &Credentials) -> bool {
+    match credentials.expiration {
+        Some(expiration) => Utc::now() + Duration::seconds(REFRESH_MARGIN_SECONDS) >= expiration,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_key() -> CacheKey {
+        CacheKey::new(&AssumeRoleRequest {
+            role_arn: "arn:aws:iam::123456789012:role/tasky".to_owned(),
+            role_session_name: "tasky".to_owned(),
+            ..Default::default()
+        }, &Region::EuWest1, CacheSource::Manager, None)
+    }
+
+    fn build_credentials(expires_in: Duration) -> Credentials {
+        Credentials {
+            aws_access_key: "access".to_owned(),
+            aws_secret_key: "secret".into(),
+            aws_sts_token: Some("token".into()),
+            expiration: Some(Utc::now() + expires_in),
+        }
+    }
+
+    #[test]
+    fn test_cache_get() {
+        let cache = CredentialCache::new();
+        cache.insert(build_key(), build_credentials(Duration::hours(1)));
+        assert!(cache.get(&build_key()).is_some());
+    }
+
+    #[test]
+    fn test_cache_get_expiring() {
+        let cache = CredentialCache::new();
+        cache.insert(build_key(), build_credentials(Duration::minutes(2)));
+        assert!(cache.get(&build_key()).is_none());
+    }
+
+    #[test]
+    fn test_cache_key_region() {
+        let cache = CredentialCache::new();
+        cache.insert(build_key(), build_credentials(Duration::hours(1)));
+        let mut key = build_key();
+        key.region = Region::UsEast1.name().to_owned();
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_cache_key_source() {
+        let cache = CredentialCache::new();
+        let key = |source: CacheSource| CacheKey { source, ..build_key() };
+        cache.insert(key(CacheSource::web_identity("token")), build_credentials(Duration::hours(1)));
+        assert!(cache.get(&key(CacheSource::web_identity("token"))).is_some());
+        assert!(cache.get(&key(CacheSource::web_identity("bogus"))).is_none());
+        assert!(cache.get(&key(CacheSource::Manager)).is_none());
+        assert!(cache.get(&key(CacheSource::Profile("default".to_owned()))).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fetch_source() {
+        let cache = CredentialCache::new();
+        let source = CacheSource::Profile("sso".to_owned());
+        let fetches = Arc::new(Mutex::new(0));
+        let fetch = |expires_in: Duration| {
+            let fetches = fetches.clone();
+            move || async move {
+                *fetches.lock().unwrap() += 1;
+                Ok(build_credentials(expires_in))
+            }
+        };
+
+        let fetched = futures::future::join_all((0..3).map(|_| cache.get_or_fetch_source(source.clone(), fetch(Duration::hours(1)))))
+            .await;
+        assert!(fetched.iter().all(Result::is_ok));
+        assert_eq!(*fetches.lock().unwrap(), 1);
+
+        // Other sources and expiring credentials are fetched again
+        cache.get_or_fetch_source(CacheSource::Manager, fetch(Duration::minutes(2))).await.unwrap();
+        cache.get_or_fetch_source(CacheSource::Manager, fetch(Duration::minutes(2))).await.unwrap();
+        assert_eq!(*fetches.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_cache_key_workspace() {
+        let cache = CredentialCache::new();
+        let key = |workspace: Option<&str>| CacheKey { workspace: workspace.map(str::to_owned), ..build_key() };
+        cache.insert(key(Some("client-a")), build_credentials(Duration::hours(1)));
+        assert!(cache.get(&key(Some("client-a"))).is_some());
+        assert!(cache.get(&key(Some("client-b"))).is_none());
+        assert!(cache.get(&key(None)).is_none());
+    }
+}
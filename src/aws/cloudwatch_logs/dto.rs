@@ -1,9 +1,12 @@
 use serde::{Deserialize, Serialize};
 
+use crate::aws::dto::SessionOptions;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum EventType {
-    LOG,
-    TOKEN,
+    Log,
+    Token,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -37,7 +40,11 @@ pub struct EventResponse {
 
 #[derive(Debug, Deserialize)]
 pub struct LogsOptions {
+    #[serde(default)]
     pub role_arn: String,
+    /// Alias of a registered role, in place of the role_arn
+    pub role: Option<String>,
+    pub region: Option<String>,
     pub log_group: String,
     pub log_stream_name_prefix: Option<String>,
     pub next_token: Option<String>,
@@ -45,12 +52,26 @@ pub struct LogsOptions {
     pub filter_pattern: Option<String>,
     pub start_time_utc_millis: Option<i64>,
     pub end_time_utc_millis: Option<i64>,
+    #[serde(flatten)]
+    pub session: SessionOptions,
 }
 
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn test_logs_options_serialises() {}
+    use super::*;
+
+    #[tokio::test]
+    async fn test_logs_options_serialises() {
+        let options = warp::test::request()
+            .path("/logs?log_group=tasky&limit=10&profile=workload&duration_seconds=900&workspace=client-a")
+            .filter(&warp::query::<LogsOptions>())
+            .await
+            .unwrap();
+        assert_eq!(options.limit, Some(10));
+        assert_eq!(options.session.profile, Some("workload".to_owned()));
+        assert_eq!(options.session.duration_seconds, Some(900));
+        assert_eq!(options.session.workspace, Some("client-a".to_owned()));
+    }
 
     #[test]
     fn test_events_response_serialises() {}
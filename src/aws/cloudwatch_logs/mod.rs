@@ -5,7 +5,6 @@ use chrono::{Duration, Utc};
 use futures::Stream;
 use rayon::{iter::ParallelIterator, prelude::IntoParallelIterator};
 use rusoto_core::Region;
-use rusoto_credential::StaticProvider;
 use rusoto_logs::{
     CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsRequest, FilterLogEventsResponse,
 };
@@ -17,26 +16,35 @@ use warp::{sse, Rejection};
 
 use dto::{EventResponse, LogsOptions};
 
+use crate::aws::cache::CredentialCache;
 use crate::aws::client;
 use crate::aws::client::HttpClient;
 use crate::aws::cloudwatch_logs::dto::EventType;
 use crate::aws::credentials::{build_credential, Credentials};
-use crate::aws::manager::Config;
+use crate::aws::profile::requested_region;
+use crate::aws::region::resolve_region;
+use crate::aws::roles::resolve_role_arn;
 use crate::error::ErrorWrapper;
 use crate::extract_rejection;
+use crate::state::AppState;
 
 pub mod dto;
 
 pub async fn get_logs_events_filter(
     logs_options: LogsOptions,
+    cache: CredentialCache,
+    state: AppState,
 ) -> Result<impl warp::Reply, Rejection> {
     info!("Query params for logs filter: {:?}", logs_options);
 
-    let config = extract_rejection!(Config::load())?;
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(logs_options.session.workspace.as_deref())))?;
+    let (role_arn, role_region) = extract_rejection!(resolve_role_arn(&logs_options.role_arn, &logs_options.role, &config))?;
     let client = Arc::new(extract_rejection!(client::new_client())?);
+    let requested_region = requested_region(&logs_options.region.clone().or(role_region), &logs_options.session.profile, &config);
+    let region = extract_rejection!(resolve_region(&requested_region, &config))?;
     let credentials =
-        extract_rejection!(build_credential(&logs_options.role_arn, &config, &client).await)?;
-    let client = build_logs_client(client.clone(), credentials);
+        extract_rejection!(build_credential(&role_arn, &logs_options.session, &config, &client, &region, &cache).await)?;
+    let client = build_logs_client(client.clone(), credentials, region);
 
     Ok(sse::reply(
         sse::keep_alive()
@@ -46,14 +54,17 @@ pub async fn get_logs_events_filter(
     ))
 }
 
-pub async fn get_logs_filter(logs_options: LogsOptions) -> Result<impl warp::Reply, Rejection> {
+pub async fn get_logs_filter(logs_options: LogsOptions, cache: CredentialCache, state: AppState) -> Result<impl warp::Reply, Rejection> {
     info!("Query params for logs filter: {:?}", logs_options);
 
-    let config = extract_rejection!(Config::load())?;
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(logs_options.session.workspace.as_deref())))?;
+    let (role_arn, role_region) = extract_rejection!(resolve_role_arn(&logs_options.role_arn, &logs_options.role, &config))?;
     let client = Arc::new(extract_rejection!(client::new_client())?);
+    let requested_region = requested_region(&logs_options.region.clone().or(role_region), &logs_options.session.profile, &config);
+    let region = extract_rejection!(resolve_region(&requested_region, &config))?;
     let credentials =
-        extract_rejection!(build_credential(&logs_options.role_arn, &config, &client).await)?;
-    let client = build_logs_client(client.clone(), credentials);
+        extract_rejection!(build_credential(&role_arn, &logs_options.session, &config, &client, &region, &cache).await)?;
+    let client = build_logs_client(client.clone(), credentials, region);
 
     let mut logs: Vec<EventResponse> = extract_rejection!(get_logs(client, logs_options).await)?;
     logs.sort_by(|a, b| {
@@ -67,7 +78,7 @@ pub async fn get_logs_filter(logs_options: LogsOptions) -> Result<impl warp::Rep
 async fn sse_events(
     client: CloudWatchLogsClient,
     options: LogsOptions,
-) -> impl Stream<Item = Result<impl ServerSentEvent + Send + 'static, warp::Error>> + Send + 'static
+) -> impl Stream<Item = Result<impl ServerSentEvent + 'static, warp::Error>> + Send + 'static
 {
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -80,7 +91,7 @@ async fn sse_events(
 
                 let _token_sent = tx.send(
                     sse::json(EventResponse {
-                        event_type: EventType::TOKEN,
+                        event_type: EventType::Token,
                         event_id: None,
                         ingestion_time: None,
                         log_stream_name: None,
@@ -94,7 +105,7 @@ async fn sse_events(
                 for event in events {
                     tx.send(
                         sse::json(EventResponse {
-                            event_type: EventType::LOG,
+                            event_type: EventType::Log,
                             event_id: event.event_id,
                             ingestion_time: event.ingestion_time,
                             log_stream_name: event.log_stream_name,
@@ -130,7 +141,7 @@ async fn get_logs(
                 let events = value.events.unwrap_or_default();
 
                 let _token_sent = tx.send(EventResponse {
-                    event_type: EventType::TOKEN,
+                    event_type: EventType::Token,
                     token: value.next_token,
                     event_id: None,
                     ingestion_time: None,
@@ -141,7 +152,7 @@ async fn get_logs(
 
                 events.into_par_iter().for_each(|event| {
                     let result = tx.send(EventResponse {
-                        event_type: EventType::LOG,
+                        event_type: EventType::Log,
                         event_id: event.event_id,
                         ingestion_time: event.ingestion_time,
                         log_stream_name: event.log_stream_name,
@@ -193,14 +204,9 @@ async fn query_logs(
     Ok(logs)
 }
 
-pub fn build_logs_client(client: Arc<HttpClient>, creds: Credentials) -> CloudWatchLogsClient {
-    let cred_provider = StaticProvider::new(
-        creds.aws_access_key,
-        creds.aws_secret_key,
-        Some(creds.aws_sts_token),
-        None,
-    );
-    CloudWatchLogsClient::new_with(client, cred_provider, Region::EuWest1) //TODO update region
+pub fn build_logs_client(client: Arc<HttpClient>, creds: Credentials, region: Region) -> CloudWatchLogsClient {
+    let cred_provider = creds.build_provider();
+    CloudWatchLogsClient::new_with(client, cred_provider, region)
 }
 
 #[cfg(test)]
@@ -0,0 +1,275 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use rusoto_credential::{EnvironmentProvider, ProvideAwsCredentials};
+use rusoto_sts::WebIdentityProvider;
+use serde::{Deserialize, Serialize};
+
+use crate::aws::credentials::{parse_expiration, Credentials};
+use crate::aws::manager::Config;
+use crate::aws::secret::Secret;
+
+const INSTANCE_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+const INSTANCE_METADATA_CREDENTIALS_PATH: &str = "latest/meta-data/iam/security-credentials";
+const CONTAINER_CREDENTIALS_ENDPOINT: &str = "http://169.254.170.2";
+const METADATA_TIMEOUT_SECONDS: u64 = 2;
+
+/// Where the base credentials come from, `AwsManager` reads them from ~/.awsManager.json and ~/.aws/credentials
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialSource {
+    AwsManager,
+    Environment,
+    WebIdentity,
+    Container,
+    InstanceMetadata,
+    /// Environment, web identity, container and then instance metadata, the same order as the aws sdks
+    Chain,
+}
+
+impl Default for CredentialSource {
+    fn default() -> Self {
+        CredentialSource::AwsManager
+    }
+}
+
+/// Credentials as served by the ecs container endpoint and ec2 instance metadata
+#[derive(Debug, Deserialize)]
+struct MetadataCredentials {
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: String,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: String,
+    #[serde(rename = "Token")]
+    token: Option<String>,
+    #[serde(rename = "Expiration")]
+    expiration: Option<String>,
+}
+
+pub async fn resolve_credential_source(config: &Config) -> Result<Credentials, Error> {
+    match config.credential_source {
+        CredentialSource::AwsManager => Err(anyhow!("The aws manager credentials are not a credential source")),
+        CredentialSource::Environment => environment_credentials().await,
+        CredentialSource::WebIdentity => web_identity_credentials().await,
+        CredentialSource::Container => container_credentials(config).await,
+        CredentialSource::InstanceMetadata => instance_metadata_credentials(config).await,
+        CredentialSource::Chain => chain_credentials(config).await,
+    }
+}
+
+async fn chain_credentials(config: &Config) -> Result<Credentials, Error> {
+    let mut errors: Vec<String> = Vec::new();
+
+    match environment_credentials().await {
+        Ok(credentials) => return Ok(credentials),
+        Err(err) => errors.push(format!("environment: {}", err)),
+    }
+    match web_identity_credentials().await {
+        Ok(credentials) => return Ok(credentials),
+        Err(err) => errors.push(format!("web identity: {}", err)),
+    }
+    match container_credentials(config).await {
+        Ok(credentials) => return Ok(credentials),
+        Err(err) => errors.push(format!("container: {}", err)),
+    }
+    match instance_metadata_credentials(config).await {
+        Ok(credentials) => return Ok(credentials),
+        Err(err) => errors.push(format!("instance metadata: {}", err)),
+    }
+    Err(anyhow!(format!("No credentials found in the credential chain, {}", errors.join(", "))))
+}
+
+async fn environment_credentials() -> Result<Credentials, Error> {
+    let credentials = EnvironmentProvider::default().credentials().await?;
+    Ok(Credentials {
+        aws_access_key: credentials.aws_access_key_id().to_owned(),
+        aws_secret_key: credentials.aws_secret_access_key().into(),
+        aws_sts_token: credentials.token().clone().map(Secret::from),
+        expiration: *credentials.expires_at(),
+    })
+}
+
+async fn web_identity_credentials() -> Result<Credentials, Error> {
+    let credentials = WebIdentityProvider::from_k8s_env().credentials().await?;
+    Ok(Credentials {
+        aws_access_key: credentials.aws_access_key_id().to_owned(),
+        aws_secret_key: credentials.aws_secret_access_key().into(),
+        aws_sts_token: credentials.token().clone().map(Secret::from),
+        expiration: *credentials.expires_at(),
+    })
+}
+
+async fn container_credentials(config: &Config) -> Result<Credentials, Error> {
+    let uri = match &config.container_credentials_endpoint {
+        Some(endpoint) => endpoint.clone(),
+        None => match (
+            non_empty_env_var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            non_empty_env_var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Some(relative_uri), _) => format!("{}{}", CONTAINER_CREDENTIALS_ENDPOINT, relative_uri),
+            (None, Some(full_uri)) => full_uri,
+            (None, None) => return Err(anyhow!("Neither AWS_CONTAINER_CREDENTIALS_RELATIVE_URI or AWS_CONTAINER_CREDENTIALS_FULL_URI are set")),
+        },
+    };
+
+    let client = build_metadata_client()?;
+    let mut request = client.get(&uri);
+    if let Some(token) = non_empty_env_var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+        request = request.header("Authorization", token);
+    }
+    let body = request.send().await?
+        .error_for_status()?
+        .text().await?;
+    parse_metadata_credentials(&body)
+}
+
+async fn instance_metadata_credentials(config: &Config) -> Result<Credentials, Error> {
+    let endpoint = config.instance_metadata_endpoint
+        .clone()
+        .unwrap_or_else(|| INSTANCE_METADATA_ENDPOINT.to_owned());
+    let endpoint = endpoint.trim_end_matches('/');
+    let client = build_metadata_client()?;
+
+    // IMDSv2 needs a session token, fall back to IMDSv1 when the instance doesn't hand one out
+    let token = client.put(&format!("{}/latest/api/token", endpoint))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .send().await
+        .and_then(|response| response.error_for_status());
+    let token = match token {
+        Ok(response) => Some(response.text().await?),
+        Err(err) => {
+            debug!("No IMDSv2 token, falling back to IMDSv1: {}", err);
+            None
+        }
+    };
+    let get = |path: String| {
+        let request = client.get(&format!("{}/{}", endpoint, path));
+        match &token {
+            Some(token) => request.header("X-aws-ec2-metadata-token", token.as_str()),
+            None => request,
+        }
+    };
+
+    let role_names = get(format!("{}/", INSTANCE_METADATA_CREDENTIALS_PATH))
+        .send().await?
+        .error_for_status()?
+        .text().await?;
+    let role_name = role_names
+        .lines()
+        .next()
+        .map(|role_name| role_name.trim().to_owned())
+        .filter(|role_name| !role_name.is_empty())
+        .ok_or_else(|| anyhow!("No instance profile role attached to this instance"))?;
+    let body = get(format!("{}/{}", INSTANCE_METADATA_CREDENTIALS_PATH, role_name))
+        .send().await?
+        .error_for_status()?
+        .text().await?;
+    parse_metadata_credentials(&body)
+}
+
+fn build_metadata_client() -> Result<reqwest::Client, Error> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(METADATA_TIMEOUT_SECONDS))
+        .no_proxy()
+        .build()?)
+}
+
+fn parse_metadata_credentials(body: &str) -> Result<Credentials, Error> {
+    let credentials: MetadataCredentials = serde_json::from_str(body)
+        .with_context(|| "Invalid credentials json from the metadata endpoint")?;
+    Ok(Credentials {
+        aws_access_key: credentials.access_key_id,
+        aws_secret_key: credentials.secret_access_key.into(),
+        aws_sts_token: credentials.token.map(Secret::from),
+        expiration: credentials.expiration.and_then(|expiration| parse_expiration(&expiration)),
+    })
+}
+
+fn non_empty_env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use warp::Filter;
+
+    use super::*;
+
+    const CREDENTIALS_JSON: &str = r#"{"Code": "Success", "AccessKeyId": "access", "SecretAccessKey": "secret", "Token": "token", "Expiration": "2020-09-01T10:00:00Z"}"#;
+
+    /// Stands in for the ec2 instance metadata and ecs container credentials endpoints
+    fn serve_metadata(require_token: bool) -> SocketAddr {
+        let api_token = warp::put()
+            .and(warp::path!("latest" / "api" / "token"))
+            .map(|| "imds-token");
+        let with_token = warp::header::optional::<String>("X-aws-ec2-metadata-token")
+            .and_then(move |token: Option<String>| async move {
+                if require_token && token.as_deref() != Some("imds-token") {
+                    Err(warp::reject::not_found())
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one();
+        let role_names = warp::get()
+            .and(warp::path!("latest" / "meta-data" / "iam" / "security-credentials"))
+            .and(with_token)
+            .map(|| "tasky-instance-role\n");
+        let role = warp::get()
+            .and(warp::path!("latest" / "meta-data" / "iam" / "security-credentials" / "tasky-instance-role"))
+            .and(with_token)
+            .map(|| CREDENTIALS_JSON);
+        let container = warp::get()
+            .and(warp::path!("v2" / "credentials"))
+            .map(|| CREDENTIALS_JSON);
+
+        let (addr, server) = warp::serve(api_token.or(role).or(role_names).or(container))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_instance_metadata_credentials() {
+        let addr = serve_metadata(true);
+        let config = Config {
+            credential_source: CredentialSource::InstanceMetadata,
+            instance_metadata_endpoint: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let credentials = resolve_credential_source(&config).await.unwrap();
+        assert_eq!(credentials.aws_access_key, "access");
+        assert_eq!(credentials.aws_secret_key.expose(), "secret");
+        assert_eq!(credentials.aws_sts_token, Some("token".into()));
+        assert!(credentials.expiration.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_container_credentials() {
+        let addr = serve_metadata(false);
+        let config = Config {
+            credential_source: CredentialSource::Container,
+            container_credentials_endpoint: Some(format!("http://{}/v2/credentials", addr)),
+            ..Default::default()
+        };
+        let credentials = resolve_credential_source(&config).await.unwrap();
+        assert_eq!(credentials.aws_access_key, "access");
+    }
+
+    #[tokio::test]
+    async fn test_instance_metadata_credentials_fail() {
+        let config = Config {
+            credential_source: CredentialSource::InstanceMetadata,
+            instance_metadata_endpoint: Some("http://127.0.0.1:9".to_owned()),
+            ..Default::default()
+        };
+        assert!(resolve_credential_source(&config).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_metadata_credentials_fail() {
+        assert!(parse_metadata_credentials("<html>").is_err());
+    }
+}
@@ -1,48 +1,267 @@
 use std::result::Result;
 use std::sync::Arc;
 
-use futures::future::join_all;
 use rusoto_core::region::Region;
 use rusoto_core::request::BufferedHttpResponse;
 use rusoto_core::RusotoError;
 use rusoto_credential::StaticProvider;
-use rusoto_sts::{AssumeRoleError, AssumeRoleRequest, AssumeRoleResponse, Sts, StsClient};
+use rusoto_sts::{AssumeRoleRequest, AssumeRoleResponse, AssumeRoleWithWebIdentityRequest, Sts, StsClient};
 use anyhow::{anyhow, Error, Context};
+use chrono::{DateTime, Utc};
 
+use crate::aws::cache::{CacheKey, CacheSource, CredentialCache};
 use crate::aws::client::HttpClient;
-use crate::aws::dto::AwsMessage;
+use crate::aws::credential_source::{resolve_credential_source, CredentialSource};
+use crate::aws::dto::{AwsMessage, SessionOptions};
 use crate::aws::manager::Config;
+use crate::aws::profile::resolve_profile;
+use crate::aws::secret::Secret;
+use crate::error::CredentialError;
 
-#[derive(Debug)]
+const MIN_SESSION_DURATION: i64 = 900;
+const MAX_SESSION_DURATION: i64 = 43200;
+const MAX_SESSION_NAME_LENGTH: usize = 64;
+const MAX_CHAINED_SESSION_DURATION: i64 = 3600;
+
+#[derive(Debug, Clone)]
 pub struct Credentials {
     pub aws_access_key: String,
-    pub aws_secret_key: String,
-    pub aws_sts_token: String,
+    pub aws_secret_key: Secret,
+    pub aws_sts_token: Option<Secret>,
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+impl Credentials {
+    pub fn build_provider(&self) -> StaticProvider {
+        StaticProvider::new(
+            self.aws_access_key.clone(),
+            self.aws_secret_key.expose().to_owned(),
+            self.aws_sts_token.as_ref().map(|token| token.expose().to_owned()),
+            None,
+        )
+    }
 }
 
+/// Assumes the role, first hopping through each role in its `via` chain when one is configured
 pub async fn assume_role(
     config: &Config,
     client: Arc<HttpClient>,
     role_arn: &str,
+    session: &SessionOptions,
+    region: &Region,
+    cache: &CredentialCache,
+) -> Result<Credentials, Error> {
+    // A web identity token stands in for the source credentials unless a profile was picked
+    let web_identity_token = match session.profile {
+        Some(_) => None,
+        None => read_web_identity_token(session, config)?,
+    };
+    let mut source = match web_identity_token {
+        Some(_) => None,
+        None => build_source_credentials(config, client.clone(), session, region, cache).await?,
+    };
+    let cache_source = build_cache_source(config, session, &web_identity_token);
+    if role_arn.is_empty() {
+        return source.ok_or_else(|| anyhow!("Either a role_arn or an aws profile is required"));
+    }
+
+    let chain = build_role_chain(role_arn, config)?;
+    for (index, hop_arn) in chain.iter().enumerate() {
+        let hop_session = if index == chain.len() - 1 {
+            session.clone()
+        } else {
+            // External ids and session policies belong to the target role only
+            SessionOptions {
+                role_session_name: session.role_session_name.clone(),
+                token_code: session.token_code.clone(),
+                ..Default::default()
+            }
+        };
+        let credentials = match (&web_identity_token, &source) {
+            (Some(web_identity_token), None) => {
+                assume_web_identity_role(config, client.clone(), hop_arn, web_identity_token, &hop_session, region, cache).await
+            }
+            _ => match build_single_role_request(hop_arn, &hop_session, config, source.is_some()) {
+                Ok(assume_role_request) => {
+                    debug!("Assuming role with config: {:?} and role_arn: {:?} in {}", config, hop_arn, region.name());
+                    let cache_key = CacheKey::new(&assume_role_request, region, cache_source.clone(), config.workspace.clone());
+                    assume_with_provider(client.clone(), assume_role_request, region, cache, cache_key, || match &source {
+                        Some(source) => Ok(source.build_provider()),
+                        None if config.is_token_valid() => build_static_provider(config),
+                        None => Err(anyhow!("Token is not valid")),
+                    }).await
+                }
+                Err(err) => Err(err),
+            },
+        }
+            .with_context(|| format!("Failed assuming {} on the way to {}", hop_arn, role_arn))?;
+        source = Some(credentials);
+    }
+    source.ok_or_else(|| anyhow!(format!("No roles to assume for {}", role_arn)))
+}
+
+/// Matches the source `build_source_credentials` picks, roles assumed further along a chain are cached
+/// under the credentials the chain started from
+fn build_cache_source(config: &Config, session: &SessionOptions, web_identity_token: &Option<String>) -> CacheSource {
+    if let Some(web_identity_token) = web_identity_token {
+        return CacheSource::web_identity(web_identity_token);
+    }
+    if let Some(profile) = session.profile.as_ref().or(config.aws_profile.as_ref()) {
+        return CacheSource::Profile(profile.clone());
+    }
+    match config.credential_source {
+        CredentialSource::AwsManager => CacheSource::Manager,
+        credential_source => CacheSource::CredentialSource(credential_source),
+    }
+}
+
+fn read_web_identity_token(session: &SessionOptions, config: &Config) -> Result<Option<String>, Error> {
+    if let Some(web_identity_token) = &session.web_identity_token {
+        return Ok(Some(web_identity_token.expose().trim().to_owned()));
+    }
+    match &config.web_identity_token_file {
+        Some(token_file) => {
+            let web_identity_token = std::fs::read_to_string(token_file)
+                .with_context(|| format!("could not read web identity token file {}", token_file))?;
+            Ok(Some(web_identity_token.trim().to_owned()))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn assume_web_identity_role(
+    config: &Config,
+    client: Arc<HttpClient>,
+    role_arn: &str,
+    web_identity_token: &str,
+    session: &SessionOptions,
+    region: &Region,
+    cache: &CredentialCache,
 ) -> Result<Credentials, Error> {
-    debug!("Assuming role with config: {:?} and role_arn: {:?}", config, role_arn);
-    if config.is_token_valid() {
-        let cred_provider = build_static_provider(config)?;
-        let response = request_assume_role(client, role_arn, cred_provider).await?;
-        let credentials = extract_credentials(response)?;
-
-        Ok(Credentials {
-            aws_access_key: credentials.access_key_id,
-            aws_secret_key: credentials.secret_access_key,
-            aws_sts_token: credentials.session_token,
+    let assume_role_request = build_assume_role_request(role_arn, session, config)?;
+    let cache_source = CacheSource::web_identity(web_identity_token);
+    let cache_key = CacheKey::new(&assume_role_request, region, cache_source, config.workspace.clone());
+    if let Some(credentials) = cache.get(&cache_key) {
+        return Ok(credentials);
+    }
+
+    debug!("Assuming role with web identity for arn: {} in {}", role_arn, region.name());
+    // AssumeRoleWithWebIdentity is authorised by the token, so the request isn't signed
+    let sts_client = StsClient::new_with_client(rusoto_core::Client::new_not_signing(client), region.clone());
+    let response = sts_client
+        .assume_role_with_web_identity(AssumeRoleWithWebIdentityRequest {
+            role_arn: assume_role_request.role_arn,
+            role_session_name: assume_role_request.role_session_name,
+            duration_seconds: assume_role_request.duration_seconds,
+            policy: assume_role_request.policy,
+            web_identity_token: web_identity_token.to_owned(),
+            ..Default::default()
         })
-    } else {
-        Err(anyhow!("Token is not valid"))
+        .await
+        .map_err(match_rusoto_errors)?;
+    let credentials = response.credentials
+        .ok_or_else(|| anyhow!("Missing credentials from assume role with web identity"))?;
+
+    let credentials = Credentials {
+        expiration: parse_expiration(&credentials.expiration),
+        aws_access_key: credentials.access_key_id,
+        aws_secret_key: credentials.secret_access_key.into(),
+        aws_sts_token: Some(credentials.session_token.into()),
+    };
+    cache.insert(cache_key, credentials.clone());
+    Ok(credentials)
+}
+
+/// Credentials of the selected aws profile or credential source, or none when the manager config credentials
+/// should be used
+async fn build_source_credentials(
+    config: &Config,
+    client: Arc<HttpClient>,
+    session: &SessionOptions,
+    region: &Region,
+    cache: &CredentialCache,
+) -> Result<Option<Credentials>, Error> {
+    if let Some(profile) = session.profile.as_ref().or(config.aws_profile.as_ref()) {
+        debug!("Using aws profile {} as the source credentials", profile);
+        return Ok(Some(resolve_profile(profile, &config.profiles, client, session, region, cache).await?));
+    }
+    match config.credential_source {
+        CredentialSource::AwsManager => Ok(None),
+        credential_source => {
+            debug!("Using {:?} as the source credentials", credential_source);
+            let cache_source = CacheSource::CredentialSource(credential_source);
+            Ok(Some(cache.get_or_fetch_source(cache_source, || resolve_credential_source(config)).await?))
+        }
+    }
+}
+
+fn build_role_chain(role_arn: &str, config: &Config) -> Result<Vec<String>, Error> {
+    let mut chain: Vec<String> = config.roles
+        .get(role_arn)
+        .map(|role| role.via.clone())
+        .unwrap_or_default();
+    chain.push(role_arn.to_owned());
+
+    for (index, hop_arn) in chain.iter().enumerate() {
+        if chain[..index].contains(hop_arn) {
+            return Err(anyhow!(format!("Role chain for {} visits {} more than once", role_arn, hop_arn)));
+        }
+    }
+    Ok(chain)
+}
+
+/// The AssumeRole request for one hop, chained hops assume the role with the previous hop's credentials
+fn build_single_role_request(role_arn: &str, session: &SessionOptions, config: &Config, is_chained: bool) -> Result<AssumeRoleRequest, Error> {
+    let mut assume_role_request = build_assume_role_request(role_arn, session, config)?;
+    if is_chained {
+        // AWS caps chained role sessions at an hour
+        assume_role_request.duration_seconds = assume_role_request.duration_seconds
+            .map(|duration_seconds| duration_seconds.min(MAX_CHAINED_SESSION_DURATION));
+    }
+    Ok(assume_role_request)
+}
+
+/// Assumes the role unless it is already cached, the provider is only built when sts has to be called
+pub async fn assume_with_provider<F>(
+    client: Arc<HttpClient>,
+    assume_role_request: AssumeRoleRequest,
+    region: &Region,
+    cache: &CredentialCache,
+    cache_key: CacheKey,
+    build_provider: F,
+) -> Result<Credentials, Error>
+    where F: FnOnce() -> Result<StaticProvider, Error>, {
+    if let Some(credentials) = cache.get(&cache_key) {
+        return Ok(credentials);
+    }
+
+    let role_arn = assume_role_request.role_arn.clone();
+    if let (Some(serial_number), None) = (&assume_role_request.serial_number, &assume_role_request.token_code) {
+        return Err(anyhow!(CredentialError::MfaRequired {
+            role_arn,
+            serial_number: serial_number.clone(),
+        }));
     }
+
+    let sent_mfa = assume_role_request.serial_number.is_some() && assume_role_request.token_code.is_some();
+    let cred_provider = build_provider()?;
+    let response = request_assume_role(client, assume_role_request, cred_provider, region)
+        .await
+        .map_err(|err| match_mfa_errors(err, &role_arn, sent_mfa))?;
+    let credentials = extract_credentials(response)?;
+
+    let credentials = Credentials {
+        expiration: parse_expiration(&credentials.expiration),
+        aws_access_key: credentials.access_key_id,
+        aws_secret_key: credentials.secret_access_key.into(),
+        aws_sts_token: Some(credentials.session_token.into()),
+    };
+    cache.insert(cache_key, credentials.clone());
+    Ok(credentials)
 }
 
 fn extract_credentials(assume_role_res: AssumeRoleResponse) -> Result<rusoto_sts::Credentials, Error> {
-    Ok(assume_role_res
+    assume_role_res
         .clone()
         .credentials
         .ok_or_else(|| {
@@ -51,30 +270,60 @@ fn extract_credentials(assume_role_res: AssumeRoleResponse) -> Result<rusoto_sts
                 assume_role_res
             ))
         })
-        .with_context(|| "Missing credentials from assume role")?)
+        .with_context(|| "Missing credentials from assume role")
+}
+
+pub fn parse_expiration(expiration: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(expiration) {
+        Ok(expiration) => Some(expiration.with_timezone(&Utc)),
+        Err(err) => {
+            error!("Failed to parse credential expiration `{}`: {}", expiration, err);
+            None
+        }
+    }
 }
 
 fn build_static_provider(config: &Config) -> Result<StaticProvider, Error> {
-    Ok(StaticProvider::new(
-        config
+    Ok(build_config_credentials(config)?.build_provider())
+}
+
+/// The session held in the manager config
+fn build_config_credentials(config: &Config) -> Result<Credentials, Error> {
+    Ok(Credentials {
+        aws_access_key: config
             .aws_temp_access_key_id
             .clone()
             .ok_or_else(|| anyhow!("aws_temp_access_key_id is not set"))?,
-        config
+        aws_secret_key: config
             .aws_temp_secret_access_key
             .clone()
             .ok_or_else(|| anyhow!("aws_temp_secret_access_keys not set"))?,
-        config.aws_session_token.clone(),
-        None,
-    ))
+        aws_sts_token: config.aws_session_token.clone(),
+        expiration: config.aws_session_expiration.map(|expiration| expiration.with_timezone(&Utc)),
+    })
 }
 
-async fn request_assume_role(client: Arc<HttpClient>, role_arn: &str, cred_provider: StaticProvider) -> Result<AssumeRoleResponse, Error> {
-    let sts_client = StsClient::new_with(client, cred_provider, Region::EuWest1);
+/// The credentials requests start from before any role is assumed, a profile or credential source when one
+/// is picked and otherwise the manager config session
+async fn build_base_credential(
+    session: &SessionOptions,
+    config: &Config,
+    client: &Arc<HttpClient>,
+    region: &Region,
+    cache: &CredentialCache,
+) -> Result<Credentials, Error> {
+    match build_source_credentials(config, client.clone(), session, region, cache).await? {
+        Some(credentials) => Ok(credentials),
+        None if config.is_token_valid() => build_config_credentials(config),
+        None => Err(anyhow!("Token is not valid")),
+    }
+}
 
-    debug!("Assuming role for arn: {}", role_arn.to_string());
+async fn request_assume_role(client: Arc<HttpClient>, assume_role_request: AssumeRoleRequest, cred_provider: StaticProvider, region: &Region) -> Result<AssumeRoleResponse, Error> {
+    // Sts resolves to the regional endpoint, eg sts.ap-southeast-2.amazonaws.com
+    let sts_client = StsClient::new_with(client, cred_provider, region.clone());
 
-    let assume_role_request = build_assume_role_request(role_arn);
+    debug!("Assuming role for arn: {}", assume_role_request.role_arn);
 
     let response = sts_client
         .assume_role(assume_role_request)
@@ -86,15 +335,86 @@ async fn request_assume_role(client: Arc<HttpClient>, role_arn: &str, cred_provi
     }
 }
 
-fn build_assume_role_request(role_arn: &str) -> AssumeRoleRequest {
-    AssumeRoleRequest {
+fn build_assume_role_request(role_arn: &str, session: &SessionOptions, config: &Config) -> Result<AssumeRoleRequest, Error> {
+    let role_session_name = session.role_session_name.clone()
+        .or_else(|| config.role_session_name.clone())
+        .unwrap_or_else(default_session_name);
+    validate_session_name(&role_session_name)?;
+
+    let duration_seconds = session.duration_seconds.or(config.duration_seconds);
+    if let Some(duration_seconds) = duration_seconds {
+        validate_duration_seconds(duration_seconds)?;
+    }
+
+    let serial_number = config.roles
+        .get(role_arn)
+        .and_then(|role| role.mfa_serial.clone());
+    let token_code = match serial_number {
+        Some(_) => session.token_code.clone().filter(|token_code| !token_code.trim().is_empty()),
+        None => None,
+    };
+
+    Ok(AssumeRoleRequest {
         role_arn: role_arn.to_owned(),
-        role_session_name: "dummy".to_owned(),
+        role_session_name,
+        duration_seconds,
+        external_id: session.external_id.clone().or_else(|| config.external_id.clone()),
+        policy: session.session_policy.clone().or_else(|| config.session_policy.clone()),
+        serial_number,
+        token_code,
         ..Default::default()
+    })
+}
+
+/// Session name of `tasky-<local user>` so CloudTrail can tell users apart
+pub fn default_session_name() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    let user: String = user
+        .chars()
+        .filter(|c| is_session_name_char(*c))
+        .collect();
+    let mut session_name = if user.is_empty() {
+        "tasky".to_owned()
+    } else {
+        format!("tasky-{}", user)
+    };
+    session_name.truncate(MAX_SESSION_NAME_LENGTH);
+    session_name
+}
+
+pub fn validate_duration_seconds(duration_seconds: i64) -> Result<(), Error> {
+    if !(MIN_SESSION_DURATION..=MAX_SESSION_DURATION).contains(&duration_seconds) {
+        return Err(anyhow!(format!(
+            "duration_seconds must be between {} and {}, was {}",
+            MIN_SESSION_DURATION, MAX_SESSION_DURATION, duration_seconds
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_session_name(session_name: &str) -> Result<(), Error> {
+    if session_name.len() < 2 || session_name.len() > MAX_SESSION_NAME_LENGTH {
+        return Err(anyhow!(format!(
+            "role_session_name must be between 2 and {} characters, was `{}`",
+            MAX_SESSION_NAME_LENGTH, session_name
+        )));
     }
+    if !session_name.chars().all(is_session_name_char) {
+        return Err(anyhow!(format!(
+            "role_session_name may only contain alphanumerics and +=,.@-_ but was `{}`",
+            session_name
+        )));
+    }
+    Ok(())
+}
+
+fn is_session_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "+=,.@-_".contains(c)
 }
 
-fn match_rusoto_errors(err: RusotoError<AssumeRoleError>) -> Error {
+pub fn match_rusoto_errors<E: std::error::Error + 'static>(err: RusotoError<E>) -> Error {
     match err {
         RusotoError::Service(err) => anyhow!(format!("{}", err)),
         RusotoError::HttpDispatch(err) => anyhow!(format!("{}", err)),
@@ -106,6 +426,20 @@ fn match_rusoto_errors(err: RusotoError<AssumeRoleError>) -> Error {
     }
 }
 
+/// AWS reports MFA failures as access denied, pull these out so the user knows to provide a code. Only requests that
+/// sent a code can have it rejected, other access denied errors are left alone even when a role name mentions MFA.
+fn match_mfa_errors(err: Error, role_arn: &str, sent_mfa: bool) -> Error {
+    let message = format!("{}", err);
+    if sent_mfa && message.contains("MultiFactorAuthentication") {
+        anyhow!(CredentialError::MfaRejected {
+            role_arn: role_arn.to_owned(),
+            message,
+        })
+    } else {
+        err
+    }
+}
+
 fn parse_aws_xml(err: BufferedHttpResponse) -> Error {
     let doc_str = format!("{:?}", err.body);
     if let Some(idl_ix) = doc_str.find("<Message>") {
@@ -121,26 +455,59 @@ fn parse_aws_xml(err: BufferedHttpResponse) -> Error {
 
 pub async fn build_credential(
     role_arn: &str,
+    session: &SessionOptions,
     config: &Config,
     client: &Arc<HttpClient>,
+    region: &Region,
+    cache: &CredentialCache,
 ) -> Result<Credentials, Error> {
-    assume_role(&config, client.clone(), &role_arn).await
+    // Requests without a role or a profile use the configured default role
+    let role_arn = match (&config.default_role_arn, &session.profile) {
+        (Some(default_role_arn), None) if role_arn.is_empty() => default_role_arn.as_str(),
+        _ => role_arn,
+    };
+    assume_role(config, client.clone(), role_arn, session, region, cache).await
 }
 
-pub async fn _build_credentials(
-    role_arns: Vec<String>,
+/// The role's credentials, or the base credentials when the request leaves the role out rather than the
+/// configured default role, for requests asking about the base credentials themselves
+pub async fn build_role_or_base_credential(
+    role_arn: &str,
+    session: &SessionOptions,
     config: &Config,
     client: &Arc<HttpClient>,
-) -> Vec<Credentials> {
-    let get_creds_futures = role_arns
-        .iter()
-        .map(|role_arn| build_credential(&role_arn, &config, &client));
-    let get_creds = join_all(get_creds_futures).await;
-    get_creds
-        .into_iter()
-        .filter(|res| res.is_ok())
-        .map(|res| res.unwrap())
-        .collect::<Vec<Credentials>>()
+    region: &Region,
+    cache: &CredentialCache,
+) -> Result<Credentials, Error> {
+    if role_arn.is_empty() {
+        build_base_credential(session, config, client, region, cache).await
+    } else {
+        build_credential(role_arn, session, config, client, region, cache).await
+    }
+}
+
+/// Checks the arn is an iam role, eg `arn:aws:iam::123456789012:role/tasky`
+pub fn validate_role_arn(role_arn: &str) -> Result<(), Error> {
+    let parts: Vec<&str> = role_arn.splitn(6, ':').collect();
+    let is_role = parts.len() == 6
+        && parts[0] == "arn"
+        && parts[2] == "iam"
+        && parts[4].len() == 12
+        && parts[4].chars().all(|c| c.is_ascii_digit())
+        && parts[5].starts_with("role/")
+        && parts[5].len() > "role/".len();
+    if !is_role {
+        return Err(anyhow!(format!("`{}` is not an iam role arn", role_arn)));
+    }
+    Ok(())
+}
+
+/// Takes the account id out of an arn, eg `arn:aws:iam::123456789012:role/tasky` gives `123456789012`
+pub fn account_id_from_arn(arn: &str) -> Option<String> {
+    arn.split(':')
+        .nth(4)
+        .filter(|account_id| !account_id.is_empty())
+        .map(|account_id| account_id.to_owned())
 }
 
 fn _iterate_credentials<T, F>(
@@ -155,4 +522,229 @@ fn _iterate_credentials<T, F>(
         .collect()
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::manager::RoleConfig;
+    use crate::test_utils::write_temp_file;
+
+    #[test]
+    fn test_account_id_from_arn() {
+        assert_eq!(account_id_from_arn("arn:aws:iam::123456789012:role/tasky"), Some("123456789012".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_expiration() {
+        let expiration = parse_expiration("2020-09-01T10:00:00Z").unwrap();
+        assert_eq!(expiration.timestamp(), 1598954400);
+    }
+
+    #[test]
+    fn test_parse_expiration_fail() {
+        assert_eq!(parse_expiration("tomorrow"), None);
+    }
+
+    #[test]
+    fn test_build_assume_role_request() {
+        let config = Config {
+            role_session_name: Some("config-session".to_owned()),
+            duration_seconds: Some(3600),
+            external_id: Some("config-external".to_owned()),
+            ..Default::default()
+        };
+        let session = SessionOptions {
+            role_session_name: Some("request-session".to_owned()),
+            session_policy: Some("{}".to_owned()),
+            ..Default::default()
+        };
+        let request = build_assume_role_request("arn:aws:iam::123456789012:role/tasky", &session, &config).unwrap();
+        assert_eq!(request.role_session_name, "request-session");
+        assert_eq!(request.duration_seconds, Some(3600));
+        assert_eq!(request.external_id, Some("config-external".to_owned()));
+        assert_eq!(request.policy, Some("{}".to_owned()));
+    }
+
+    #[test]
+    fn test_build_assume_role_request_default_session_name() {
+        let request = build_assume_role_request("arn", &SessionOptions::default(), &Config::default()).unwrap();
+        assert!(request.role_session_name.starts_with("tasky"));
+        assert!(validate_session_name(&request.role_session_name).is_ok());
+    }
+
+    #[test]
+    fn test_build_assume_role_request_fail() {
+        let session = SessionOptions {
+            duration_seconds: Some(60),
+            ..Default::default()
+        };
+        assert!(build_assume_role_request("arn", &session, &Config::default()).is_err());
+        let session = SessionOptions {
+            role_session_name: Some("has spaces".to_owned()),
+            ..Default::default()
+        };
+        assert!(build_assume_role_request("arn", &session, &Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_build_assume_role_request_mfa() {
+        let role_arn = "arn:aws:iam::123456789012:role/tasky";
+        let mut config = Config::default();
+        config.roles.insert(role_arn.to_owned(), RoleConfig {
+            mfa_serial: Some("arn:aws:iam::123456789012:mfa/user".to_owned()),
+            ..Default::default()
+        });
+        let session = SessionOptions {
+            token_code: Some("123456".to_owned()),
+            ..Default::default()
+        };
+        let request = build_assume_role_request(role_arn, &session, &config).unwrap();
+        assert_eq!(request.serial_number, Some("arn:aws:iam::123456789012:mfa/user".to_owned()));
+        assert_eq!(request.token_code, Some("123456".to_owned()));
+
+        let request = build_assume_role_request("arn:aws:iam::123456789012:role/other", &session, &config).unwrap();
+        assert_eq!(request.serial_number, None);
+        assert_eq!(request.token_code, None);
+    }
+
+    #[tokio::test]
+    async fn test_assume_role_mfa_required() {
+        let role_arn = "arn:aws:iam::123456789012:role/tasky";
+        let mut config = Config::default();
+        config.roles.insert(role_arn.to_owned(), RoleConfig {
+            mfa_serial: Some("arn:aws:iam::123456789012:mfa/user".to_owned()),
+            ..Default::default()
+        });
+        let client = Arc::new(crate::aws::client::new_client().unwrap());
+        let err = assume_role(&config, client, role_arn, &SessionOptions::default(), &Region::EuWest1, &CredentialCache::new())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<CredentialError>().is_some());
+    }
+
+    #[test]
+    fn test_read_web_identity_token() {
+        let token_file = write_temp_file("web-identity-token", "file-token\n");
+        let config = Config {
+            web_identity_token_file: Some(token_file.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let session = SessionOptions {
+            web_identity_token: Some("request-token".into()),
+            ..Default::default()
+        };
+        assert_eq!(read_web_identity_token(&session, &config).unwrap(), Some("request-token".to_owned()));
+        assert_eq!(read_web_identity_token(&SessionOptions::default(), &config).unwrap(), Some("file-token".to_owned()));
+        assert_eq!(read_web_identity_token(&SessionOptions::default(), &Config::default()).unwrap(), None);
+        std::fs::remove_file(token_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_assume_web_identity_role_cache() {
+        let role_arn = "arn:aws:iam::123456789012:role/tasky";
+        let config = Config::default();
+        let session = SessionOptions::default();
+        let cache = CredentialCache::new();
+        let request = build_assume_role_request(role_arn, &session, &config).unwrap();
+        cache.insert(CacheKey::new(&request, &Region::EuWest1, CacheSource::web_identity("token"), None), Credentials {
+            aws_access_key: "access".to_owned(),
+            aws_secret_key: "secret".into(),
+            aws_sts_token: Some("session".into()),
+            expiration: Some(Utc::now() + chrono::Duration::hours(1)),
+        });
+        let client = Arc::new(crate::aws::client::new_client().unwrap());
+        let credentials = assume_web_identity_role(&config, client, role_arn, "token", &session, &Region::EuWest1, &cache)
+            .await
+            .unwrap();
+        assert_eq!(credentials.aws_access_key, "access");
 
+        // A different token, or none at all, has to go to sts rather than reuse the cached session
+        let mut key = CacheKey::new(&request, &Region::EuWest1, CacheSource::web_identity("bogus"), None);
+        assert!(cache.get(&key).is_none());
+        key.source = build_cache_source(&config, &session, &None);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_build_cache_source() {
+        let session = SessionOptions::default();
+        assert_eq!(build_cache_source(&Config::default(), &session, &None), CacheSource::Manager);
+        assert_eq!(
+            build_cache_source(&Config::default(), &session, &Some("token".to_owned())),
+            CacheSource::web_identity("token")
+        );
+        let config = Config {
+            credential_source: CredentialSource::Container,
+            ..Default::default()
+        };
+        assert_eq!(build_cache_source(&config, &session, &None), CacheSource::CredentialSource(CredentialSource::Container));
+        let session = SessionOptions {
+            profile: Some("dev".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(build_cache_source(&config, &session, &None), CacheSource::Profile("dev".to_owned()));
+    }
+
+    #[test]
+    fn test_read_web_identity_token_fail() {
+        let config = Config {
+            web_identity_token_file: Some("/does/not/exist".to_owned()),
+            ..Default::default()
+        };
+        assert!(read_web_identity_token(&SessionOptions::default(), &config).is_err());
+    }
+
+    #[test]
+    fn test_build_role_chain() {
+        let role_arn = "arn:aws:iam::222222222222:role/workload";
+        let mut config = Config::default();
+        config.roles.insert(role_arn.to_owned(), RoleConfig {
+            via: vec!["arn:aws:iam::111111111111:role/identity".to_owned()],
+            ..Default::default()
+        });
+        let chain = build_role_chain(role_arn, &config).unwrap();
+        assert_eq!(chain, vec!["arn:aws:iam::111111111111:role/identity".to_owned(), role_arn.to_owned()]);
+        assert_eq!(build_role_chain("arn", &config).unwrap(), vec!["arn".to_owned()]);
+    }
+
+    #[test]
+    fn test_build_role_chain_fail() {
+        let role_arn = "arn:aws:iam::222222222222:role/workload";
+        let mut config = Config::default();
+        config.roles.insert(role_arn.to_owned(), RoleConfig {
+            via: vec![role_arn.to_owned()],
+            ..Default::default()
+        });
+        assert!(build_role_chain(role_arn, &config).is_err());
+    }
+
+    #[test]
+    fn test_match_mfa_errors() {
+        let err = match_mfa_errors(anyhow!("MultiFactorAuthentication failed with invalid MFA one time pass code."), "arn", true);
+        assert!(err.downcast_ref::<CredentialError>().is_some());
+        let err = match_mfa_errors(anyhow!("Access denied"), "arn", true);
+        assert!(err.downcast_ref::<CredentialError>().is_none());
+        let err = match_mfa_errors(
+            anyhow!("User: arn:aws:iam::123456789012:user/dev is not authorized to perform: sts:AssumeRole on resource: arn:aws:iam::123456789012:role/admin-MFA-required"),
+            "arn",
+            true,
+        );
+        assert!(err.downcast_ref::<CredentialError>().is_none());
+        let err = match_mfa_errors(anyhow!("MultiFactorAuthentication failed with invalid MFA one time pass code."), "arn", false);
+        assert!(err.downcast_ref::<CredentialError>().is_none());
+    }
+
+    #[test]
+    fn test_account_id_from_arn_fail() {
+        assert_eq!(account_id_from_arn("tasky"), None);
+        assert_eq!(account_id_from_arn("arn:aws:s3:::bucket"), None);
+    }
+
+    #[test]
+    fn test_validate_role_arn() {
+        assert!(validate_role_arn("arn:aws:iam::123456789012:role/tasky").is_ok());
+        assert!(validate_role_arn("arn:aws:iam::123456789012:role/path/tasky").is_ok());
+        assert!(validate_role_arn("arn:aws:iam::123456789012:user/tasky").is_err());
+        assert!(validate_role_arn("arn:aws:iam::1234:role/tasky").is_err());
+        assert!(validate_role_arn("tasky").is_err());
+    }
+}
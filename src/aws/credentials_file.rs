@@ -0,0 +1,283 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Context, Error};
+
+use crate::aws::credentials::Credentials;
+use crate::aws::profile::build_credentials_path;
+use crate::aws::secret::Secret;
+
+const ACCESS_KEY: &str = "aws_access_key_id";
+const SECRET_KEY: &str = "aws_secret_access_key";
+const SESSION_TOKEN: &str = "aws_session_token";
+
+/// Tells apart the temp files of writes running at the same time
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes the credentials to the profile in the shared credentials file so the aws cli and other tools pick
+/// them up. Only the profile's credential keys change, everything else in the file is kept as is.
+pub fn write_profile_credentials(profile: &str, credentials: &Credentials) -> Result<PathBuf, Error> {
+    let file_path = build_credentials_path().or_else(|_| build_default_credentials_path())?;
+    write_credentials_file(&file_path, profile, credentials)?;
+    Ok(file_path)
+}
+
+fn write_credentials_file(file_path: &Path, profile: &str, credentials: &Credentials) -> Result<(), Error> {
+    let contents = if file_path.exists() {
+        fs::read_to_string(file_path).with_context(|| format!("could not read {:?}", file_path))?
+    } else {
+        String::new()
+    };
+    let updated = update_profile(&contents, profile, &[
+        (ACCESS_KEY, Some(credentials.aws_access_key.as_str())),
+        (SECRET_KEY, Some(credentials.aws_secret_key.expose())),
+        (SESSION_TOKEN, credentials.aws_sts_token.as_ref().map(Secret::expose)),
+    ]);
+
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("could not create {:?}", parent))?;
+    }
+    if file_path.exists() {
+        let backup_path = with_suffix(file_path, "bak");
+        fs::copy(file_path, &backup_path).with_context(|| format!("could not back up {:?} to {:?}", file_path, backup_path))?;
+    }
+
+    replace_file(file_path, updated.as_bytes())
+}
+
+/// Writes next to the file and renames over it so other tools never read a half written file. The temp file
+/// is only readable by the owner from the moment it is created.
+pub fn replace_file(file_path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+    let temp_path = with_suffix(file_path, &format!("tmp-{}-{}", std::process::id(), count));
+    let written = create_private_file(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .with_context(|| format!("could not write {:?}", temp_path))
+        .and_then(|_| fs::rename(&temp_path, file_path).with_context(|| format!("could not replace {:?}", file_path)));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Sets the keys in the profile's section, keys set to None are removed and the section is added when missing
+fn update_profile(contents: &str, profile: &str, values: &[(&str, Option<&str>)]) -> String {
+    let mut lines: Vec<String> = contents.lines().map(|line| line.to_owned()).collect();
+    let mut written: Vec<&str> = Vec::new();
+
+    let section = find_section(&lines, profile);
+    let (start, mut end) = match section {
+        Some(section) => section,
+        None => {
+            if lines.last().map(|line| !line.trim().is_empty()).unwrap_or(false) {
+                lines.push(String::new());
+            }
+            lines.push(format!("[{}]", profile));
+            (lines.len() - 1, lines.len())
+        }
+    };
+
+    let mut index = start + 1;
+    while index < end {
+        let key = line_key(&lines[index]);
+        match values.iter().find(|(name, _)| Some(*name) == key) {
+            Some((name, Some(value))) => {
+                lines[index] = format!("{} = {}", name, value);
+                written.push(name);
+                index += 1;
+            }
+            Some((_, None)) => {
+                lines.remove(index);
+                end -= 1;
+            }
+            None => index += 1,
+        }
+    }
+
+    // New keys go after the last entry of the section rather than after its trailing blank lines
+    let mut insert_at = end;
+    while insert_at > start + 1 && lines[insert_at - 1].trim().is_empty() {
+        insert_at -= 1;
+    }
+    for (name, value) in values {
+        if let Some(value) = value {
+            if !written.contains(name) {
+                lines.insert(insert_at, format!("{} = {}", name, value));
+                insert_at += 1;
+            }
+        }
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    updated
+}
+
+/// Line range of the section, from its header up to the next header
+fn find_section(lines: &[String], profile: &str) -> Option<(usize, usize)> {
+    let start = lines.iter().position(|line| section_name(line) == Some(profile))?;
+    let end = lines[start + 1..]
+        .iter()
+        .position(|line| section_name(line).is_some())
+        .map(|offset| start + 1 + offset)
+        .unwrap_or_else(|| lines.len());
+    Some((start, end))
+}
+
+fn section_name(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('[') && line.ends_with(']') {
+        Some(line[1..line.len() - 1].trim())
+    } else {
+        None
+    }
+}
+
+fn line_key(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    line.find('=').map(|index| line[..index].trim())
+}
+
+pub fn with_suffix(file_path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", suffix));
+    file_path.with_file_name(file_name)
+}
+
+#[cfg(unix)]
+fn create_private_file(file_path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(file_path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(file_path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(file_path)
+}
+
+#[cfg(unix)]
+pub fn restrict_permissions(file_path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(file_path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("could not set permissions on {:?}", file_path))
+}
+
+#[cfg(not(unix))]
+pub fn restrict_permissions(_file_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+fn build_default_credentials_path() -> Result<PathBuf, Error> {
+    let home_dir = dirs::home_dir().ok_or_else(|| anyhow!("Missing home directory"))?;
+    Ok(Path::new(home_dir.as_path()).join(".aws/credentials"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::write_temp_file;
+
+    use super::*;
+
+    const CREDENTIALS_FILE: &str = "# managed by hand\n\
+        [default]\n\
+        aws_access_key_id = long-access\n\
+        aws_secret_access_key = long-secret\n\
+        \n\
+        ; the session profile\n\
+        [session]\n\
+        region = eu-west-1\n\
+        aws_access_key_id = old-access\n\
+        aws_session_token = old-token\n\
+        \n\
+        [other]\n\
+        aws_access_key_id = other-access\n";
+
+    fn build_credentials(token: Option<&str>) -> Credentials {
+        Credentials {
+            aws_access_key: "new-access".to_owned(),
+            aws_secret_key: "new-secret".into(),
+            aws_sts_token: token.map(Secret::from),
+            expiration: None,
+        }
+    }
+
+    fn values(credentials: &Credentials) -> Vec<(&str, Option<&str>)> {
+        vec![
+            (ACCESS_KEY, Some(credentials.aws_access_key.as_str())),
+            (SECRET_KEY, Some(credentials.aws_secret_key.expose())),
+            (SESSION_TOKEN, credentials.aws_sts_token.as_ref().map(Secret::expose)),
+        ]
+    }
+
+    #[test]
+    fn test_update_profile() {
+        let credentials = build_credentials(Some("new-token"));
+        let updated = update_profile(CREDENTIALS_FILE, "session", &values(&credentials));
+        assert_eq!(updated, "# managed by hand\n\
+            [default]\n\
+            aws_access_key_id = long-access\n\
+            aws_secret_access_key = long-secret\n\
+            \n\
+            ; the session profile\n\
+            [session]\n\
+            region = eu-west-1\n\
+            aws_access_key_id = new-access\n\
+            aws_session_token = new-token\n\
+            aws_secret_access_key = new-secret\n\
+            \n\
+            [other]\n\
+            aws_access_key_id = other-access\n");
+    }
+
+    #[test]
+    fn test_update_profile_removes_token() {
+        let credentials = build_credentials(None);
+        let updated = update_profile(CREDENTIALS_FILE, "session", &values(&credentials));
+        assert!(!updated.contains("old-token"));
+        assert!(updated.contains("[other]\naws_access_key_id = other-access\n"));
+    }
+
+    #[test]
+    fn test_update_profile_new_section() {
+        let credentials = build_credentials(Some("new-token"));
+        let updated = update_profile(CREDENTIALS_FILE, "tasky", &values(&credentials));
+        assert!(updated.starts_with(CREDENTIALS_FILE));
+        assert!(updated.ends_with("aws_access_key_id = other-access\n\n[tasky]\n\
+            aws_access_key_id = new-access\n\
+            aws_secret_access_key = new-secret\n\
+            aws_session_token = new-token\n"));
+    }
+
+    #[test]
+    fn test_write_credentials_file() {
+        let file_path = write_temp_file("write-credentials", CREDENTIALS_FILE);
+        write_credentials_file(&file_path, "session", &build_credentials(Some("new-token"))).unwrap();
+
+        let backup_path = with_suffix(&file_path, "bak");
+        assert_eq!(fs::read_to_string(&backup_path).unwrap(), CREDENTIALS_FILE);
+        assert!(fs::read_to_string(&file_path).unwrap().contains("aws_session_token = new-token"));
+        fs::remove_file(file_path).unwrap();
+        fs::remove_file(backup_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let file_path = write_temp_file("replace-file", "old");
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o644)).unwrap();
+        replace_file(&file_path, b"new").unwrap();
+
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "new");
+        assert_eq!(fs::metadata(&file_path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_file(file_path).unwrap();
+    }
+}
@@ -1,8 +1,215 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::aws::credential_source::CredentialSource;
+use crate::aws::ini::Diagnostic;
+use crate::aws::secret::Secret;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AwsRequest {
-    pub role_arn: String
+    #[serde(default)]
+    pub role_arn: String,
+    /// Alias of a registered role, in place of the role_arn
+    pub role: Option<String>,
+    pub region: Option<String>,
+    pub regions: Option<Vec<String>>,
+    #[serde(default)]
+    pub all_regions: bool,
+    #[serde(flatten)]
+    pub session: SessionOptions,
+}
+
+/// Overrides for the AssumeRole session, anything unset falls back to the manager config
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionOptions {
+    /// Aws profile from ~/.aws/config or ~/.aws/credentials to source the base credentials from
+    pub profile: Option<String>,
+    pub role_session_name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration_seconds")]
+    pub duration_seconds: Option<i64>,
+    pub external_id: Option<String>,
+    pub session_policy: Option<String>,
+    /// One time code from the role's MFA device, only needed when the cached credentials have expired
+    pub token_code: Option<String>,
+    /// OIDC token to assume the role with AssumeRoleWithWebIdentity instead of the source credentials
+    pub web_identity_token: Option<Secret>,
+    /// Workspace from the manager config the request runs in, defaults to `Config.default_workspace`
+    pub workspace: Option<String>,
+}
+
+/// Flattened into a query string every value arrives as a string, so the duration is read from either
+fn deserialize_duration_seconds<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+    where D: Deserializer<'de>, {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Duration {
+        Seconds(i64),
+        Text(String),
+    }
+    match Option::<Duration>::deserialize(deserializer)? {
+        Some(Duration::Seconds(duration_seconds)) => Ok(Some(duration_seconds)),
+        Some(Duration::Text(text)) => text.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountsRequest {
+    #[serde(default)]
+    pub role_arns: Vec<String>,
+    /// Aliases of registered roles, queried along with the role_arns
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub region: Option<String>,
+    pub regions: Option<Vec<String>>,
+    #[serde(default)]
+    pub all_regions: bool,
+    #[serde(flatten)]
+    pub session: SessionOptions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRefreshRequest {
+    /// One time code from the MFA device in `Config.mfa_serial`
+    pub token_code: Option<String>,
+    pub duration_seconds: Option<i64>,
+    /// Profile in ~/.aws/credentials the session is written to, defaults to the current sts profile
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub valid: bool,
+    /// Profile in ~/.aws/credentials the session token was read from, unset when tasky refreshed it
+    pub sts_profile: Option<String>,
+    pub expiration: Option<DateTime<FixedOffset>>,
+    pub expires_in_seconds: Option<i64>,
+    pub mfa_serial: Option<String>,
+}
+
+/// How a profile gets its credentials
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileKind {
+    Static,
+    AssumeRole,
+    Sso,
+    CredentialProcess,
+    None,
+}
+
+/// A profile without any of its secrets
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfileSummary {
+    pub name: String,
+    pub kind: ProfileKind,
+    pub region: Option<String>,
+    pub role_arn: Option<String>,
+    pub source_profile: Option<String>,
+    pub mfa_serial: Option<String>,
+    pub sso_account_id: Option<String>,
+    pub sso_role_name: Option<String>,
+    pub has_session_token: bool,
+    pub problems: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfilesResponse {
+    pub profiles: Vec<ProfileSummary>,
+    /// Problems that don't belong to a profile, eg lines before the first section
+    pub problems: Vec<Diagnostic>,
+}
+
+/// Settings that can be changed through the api, a PUT replaces all of them and a PATCH only the ones given
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigUpdate {
+    pub region: Option<String>,
+    pub enabled_regions: Option<Vec<String>>,
+    pub default_role_arn: Option<String>,
+    pub aws_sts_profile: Option<String>,
+    pub aws_profile: Option<String>,
+    pub credential_source: Option<CredentialSource>,
+    pub role_session_name: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub mfa_serial: Option<String>,
+    pub expiry_warning_minutes: Option<Vec<i64>>,
+    pub ecs_list_limit: Option<usize>,
+}
+
+/// Picks whose identity to look up, the base credentials unless a role is given
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+pub struct IdentityRequest {
+    #[serde(default)]
+    pub role_arn: String,
+    /// Alias of a registered role, in place of the role_arn
+    pub role: Option<String>,
+    pub region: Option<String>,
+    #[serde(flatten)]
+    pub session: SessionOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IdentityResponse {
+    pub account: Option<String>,
+    pub arn: Option<String>,
+    pub user_id: Option<String>,
+    /// Unset when the credentials aren't allowed to list the account aliases
+    pub account_alias: Option<String>,
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+/// Picks whose permissions to check, the base credentials unless a role is given
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+pub struct PermissionsRequest {
+    #[serde(default)]
+    pub role_arn: String,
+    /// Alias of a registered role, in place of the role_arn
+    pub role: Option<String>,
+    pub region: Option<String>,
+    /// Log group to probe logs:FilterLogEvents against
+    pub log_group: Option<String>,
+    /// `BUCKET_NAME:BUCKET_KEY` to probe s3:GetObject against, without it the probe can't tell
+    pub bucket_file: Option<String>,
+    #[serde(flatten)]
+    pub session: SessionOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allowed,
+    Denied,
+    /// The check couldn't tell, eg a probe that failed to reach aws
+    Unknown,
+}
+
+/// How the permissions were checked, probes are used when the principal can't simulate its own policies
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckMethod {
+    Simulation,
+    Probe,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActionPermission {
+    pub action: String,
+    pub decision: Decision,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PermissionsResponse {
+    pub principal_arn: Option<String>,
+    pub method: CheckMethod,
+    /// Why the policies couldn't be simulated when probes were used instead
+    pub simulation_error: Option<String>,
+    pub actions: Vec<ActionPermission>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockRequest {
+    pub passphrase: Secret,
 }
 
 #[derive(Deserialize, Debug)]
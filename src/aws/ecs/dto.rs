@@ -42,6 +42,9 @@ pub struct ServiceResponse {
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ClusterResponse {
+    /// <p>The region the cluster was queried from.</p>
+    #[serde(rename = "region")]
+    pub region: String,
     #[serde(rename = "activeServicesCount")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub active_services_count: Option<i64>,
@@ -63,7 +66,48 @@ pub struct ClusterResponse {
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ResponseWrapper {
-    pub(crate) clusters: Vec<ClusterResponse>
+    pub(crate) clusters: Vec<ClusterResponse>,
+    #[serde(default)]
+    pub(crate) errors: Vec<RegionError>,
+    /// Set when a listing stopped at `ecs_list_limit` before reaching its last page
+    #[serde(default)]
+    pub(crate) truncated: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionError {
+    #[serde(rename = "region")]
+    pub region: String,
+    #[serde(rename = "message")]
+    pub message: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    #[serde(rename = "roleArns")]
+    pub role_arns: Vec<String>,
+    #[serde(rename = "clusters")]
+    pub clusters: Vec<ClusterResponse>,
+    #[serde(rename = "errors")]
+    pub errors: Vec<RegionError>,
+    #[serde(rename = "truncated")]
+    pub truncated: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoleFailure {
+    #[serde(rename = "roleArn")]
+    pub role_arn: String,
+    #[serde(rename = "message")]
+    pub message: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountsResponseWrapper {
+    pub(crate) accounts: Vec<AccountResponse>,
+    pub(crate) failures: Vec<RoleFailure>,
 }
 
 #[cfg(test)]
@@ -1,11 +1,10 @@
-use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
 
 use futures::future::join_all;
 use resiter::GetOks;
 use rusoto_core::{Region, RusotoError};
-use rusoto_credential::StaticProvider;
 use rusoto_ecs::{Cluster, DescribeClustersError, ListClustersError};
 use rusoto_ecs::DescribeClustersRequest;
 use rusoto_ecs::DescribeClustersResponse;
@@ -13,47 +12,191 @@ use rusoto_ecs::DescribeServicesRequest;
 use rusoto_ecs::Ecs;
 use rusoto_ecs::EcsClient;
 use rusoto_ecs::ListClustersRequest;
-use rusoto_ecs::ListClustersResponse;
 use rusoto_ecs::ListServicesRequest;
-use rusoto_ecs::ListServicesResponse;
 use rusoto_ecs::ListTasksRequest;
-use rusoto_ecs::ListTasksResponse;
 use rusoto_ecs::Service;
 use warp::reject;
 use warp::Rejection;
 
+use crate::aws::cache::CredentialCache;
 use crate::aws::client;
 use crate::aws::client::HttpClient;
-use crate::aws::credentials::{build_credential, Credentials};
-use crate::aws::dto::AwsRequest;
-use crate::aws::ecs::dto::{ClusterResponse, ResponseWrapper, ServiceResponse};
+use crate::aws::credentials::{account_id_from_arn, build_credential, match_rusoto_errors, Credentials};
+use crate::aws::dto::{AccountsRequest, AwsRequest};
 use crate::aws::manager::Config;
+use crate::aws::ecs::dto::{AccountResponse, AccountsResponseWrapper, ClusterResponse, RegionError, ResponseWrapper, RoleFailure, ServiceResponse};
+use crate::aws::profile::requested_region;
+use crate::aws::region::{resolve_region, resolve_regions};
+use crate::aws::roles::{resolve_role_arn, resolve_role_arns};
 use crate::error::ErrorWrapper;
 use crate::extract_rejection;
+use crate::state::AppState;
 use anyhow::{anyhow, Error};
 
 mod dto;
 
+/// Most arns one listing follows next_token for when the config has no `ecs_list_limit`
+const DEFAULT_MAX_LISTED: usize = 1000;
+/// Largest page ListClusters, ListServices and ListTasks return
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_DESCRIBE_CLUSTERS: usize = 100;
+const MAX_DESCRIBE_SERVICES: usize = 10;
+
+/// Arns from following a listing's next_token, truncated when it stopped at the limit with pages left
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Listing {
+    pub arns: Vec<String>,
+    pub truncated: bool,
+}
+
+struct EcsQuery {
+    clusters_described: DescribeClustersResponse,
+    services_described: HashMap<String, Vec<Service>>,
+    /// Task arns by service arn
+    tasks: HashMap<String, Vec<String>>,
+    truncated: bool,
+}
 
-pub async fn get_ecs_filter(request: AwsRequest) -> Result<impl warp::Reply, Rejection> {
-    let config = extract_rejection!(Config::load())?;
+pub async fn get_ecs_filter(request: AwsRequest, cache: CredentialCache, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(request.session.workspace.as_deref())))?;
+    let (role_arn, role_region) = extract_rejection!(resolve_role_arn(&request.role_arn, &request.role, &config))?;
     let client = Arc::new(extract_rejection!(client::new_client())?);
 
-    let creds = extract_rejection!(build_credential(&request.role_arn, &config, &client).await)?;
+    let requested_region = requested_region(&request.region.clone().or(role_region), &request.session.profile, &config);
+    let region = extract_rejection!(resolve_region(&requested_region, &config))?;
+    let regions = extract_rejection!(resolve_regions(&requested_region, &request.regions, request.all_regions, &config))?;
+    let creds = extract_rejection!(build_credential(&role_arn, &request.session, &config, &client, &region, &cache).await)?;
+
+    let max_items = config.ecs_list_limit.unwrap_or(DEFAULT_MAX_LISTED);
+    let result = extract_rejection!(query_regions(&client, &creds, regions, max_items).await)?;
+    Ok(warp::reply::json(&result))
+}
 
-    let ecs_client = build_ecs_client(client.clone(), creds);
+pub async fn get_ecs_accounts_filter(request: AccountsRequest, cache: CredentialCache, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(request.session.workspace.as_deref())))?;
+    let role_arns = extract_rejection!(resolve_role_arns(&request.role_arns, &request.roles, &config))?;
+    let client = Arc::new(extract_rejection!(client::new_client())?);
 
-    let query = extract_rejection!(query_ecs(ecs_client).await)?;
+    let queries = role_arns
+        .into_iter()
+        .map(|(role_arn, role_region)| {
+            let (request, config, client, cache) = (&request, &config, &client, &cache);
+            async move {
+                let result = query_role(&role_arn, role_region, request, config, client, cache).await;
+                (role_arn, result)
+            }
+        });
 
-    let result = extract_rejection!(map_to_response(query.0, query.1, query.2))?;
+    let result = group_by_account(join_all(queries).await);
     Ok(warp::reply::json(&result))
 }
 
-async fn query_ecs(client: EcsClient) -> Result<(DescribeClustersResponse, HashMap<String, Vec<Service>, RandomState>, HashMap<String, ListTasksResponse, RandomState>), Error> {
+/// Queries one role of an accounts request, in the default region of its named role unless the request picks
+/// the regions
+async fn query_role(
+    role_arn: &str,
+    role_region: Option<String>,
+    request: &AccountsRequest,
+    config: &Config,
+    client: &Arc<HttpClient>,
+    cache: &CredentialCache,
+) -> Result<ResponseWrapper, Error> {
+    let requested_region = requested_region(&request.region.clone().or(role_region), &request.session.profile, config);
+    let region = resolve_region(&requested_region, config)?;
+    let regions = resolve_regions(&requested_region, &request.regions, request.all_regions, config)?;
+    let creds = build_credential(role_arn, &request.session, config, client, &region, cache).await?;
+    query_regions(client, &creds, regions, config.ecs_list_limit.unwrap_or(DEFAULT_MAX_LISTED)).await
+}
+
+fn group_by_account(results: Vec<(String, Result<ResponseWrapper, Error>)>) -> AccountsResponseWrapper {
+    let mut response = AccountsResponseWrapper::default();
+    for (role_arn, result) in results {
+        let account_id = account_id_from_arn(&role_arn);
+        match (account_id, result) {
+            (Some(account_id), Ok(mut role_response)) => {
+                let position = response.accounts
+                    .iter()
+                    .position(|account| account.account_id == account_id);
+                let account = match position {
+                    Some(position) => &mut response.accounts[position],
+                    None => {
+                        response.accounts.push(AccountResponse {
+                            account_id,
+                            ..Default::default()
+                        });
+                        response.accounts.last_mut().unwrap()
+                    }
+                };
+                account.role_arns.push(role_arn);
+                account.clusters.append(&mut role_response.clusters);
+                account.errors.append(&mut role_response.errors);
+                account.truncated |= role_response.truncated;
+            }
+            (None, _) => {
+                response.failures.push(RoleFailure {
+                    message: format!("Could not read an account id from `{}`", role_arn),
+                    role_arn,
+                });
+            }
+            (_, Err(err)) => {
+                error!("Failed to query ecs for {}: {}", role_arn, err);
+                response.failures.push(RoleFailure {
+                    role_arn,
+                    message: format!("{}", err),
+                });
+            }
+        }
+    }
+    response.accounts.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+    response
+}
+
+async fn query_regions(client: &Arc<HttpClient>, creds: &Credentials, regions: Vec<Region>, max_items: usize) -> Result<ResponseWrapper, Error> {
+    let queries = regions
+        .into_iter()
+        .map(|region| async move {
+            let ecs_client = build_ecs_client(client.clone(), creds.clone(), region.clone());
+            let result = match query_ecs(ecs_client, max_items).await {
+                Ok(query) => map_to_response(query, &region),
+                Err(err) => Err(err),
+            };
+            (region, result)
+        });
+    merge_region_responses(join_all(queries).await)
+}
+
+fn merge_region_responses(results: Vec<(Region, Result<ResponseWrapper, Error>)>) -> Result<ResponseWrapper, Error> {
+    let queried = results.len();
+    let mut response = ResponseWrapper::default();
+    for (region, result) in results {
+        match result {
+            Ok(mut region_response) => {
+                response.clusters.append(&mut region_response.clusters);
+                response.truncated |= region_response.truncated;
+            }
+            Err(err) => {
+                error!("Failed to query ecs in {}: {}", region.name(), err);
+                response.errors.push(RegionError {
+                    region: region.name().to_owned(),
+                    message: format!("{}", err),
+                })
+            }
+        }
+    }
+    if queried > 0 && response.errors.len() == queried {
+        let messages: Vec<String> = response.errors
+            .iter()
+            .map(|err| format!("{}: {}", err.region, err.message))
+            .collect();
+        return Err(anyhow!(messages.join(", ")));
+    }
+    Ok(response)
+}
+
+async fn query_ecs(client: EcsClient, max_items: usize) -> Result<EcsQuery, Error> {
     let client = Arc::new(client);
-    let list_clusters = get_clusters(&client.clone()).await?;
-    let cluster_arns = list_clusters.cluster_arns.clone();
-    let cluster_arns_cloned = list_clusters.cluster_arns.clone();
+    let clusters = get_clusters(&client, max_items).await?;
+    let cluster_arns = clusters.arns.clone();
 
     let client_cloned = client.clone();
     let clusters_described = tokio::task::spawn(async move {
@@ -61,34 +204,38 @@ async fn query_ecs(client: EcsClient) -> Result<(DescribeClustersResponse, HashM
     });
 
     let client_cloned = client.clone();
+    let cluster_arns = clusters.arns.clone();
     let services = tokio::task::spawn(async move {
-        get_services(&client_cloned, &cluster_arns_cloned).await // Now have services mapped to cluster ids
+        get_services(&client_cloned, &cluster_arns, max_items).await // Now have services mapped to cluster ids
     });
 
     let clusters_described = clusters_described.await??; // Can take name, pending and running in here
 
     let services = services.await??; // Now have services mapped to cluster ids
-    let services_described = describe_services(&client, services.clone()).await;
-    let tasks = get_tasks(&client, services_described.clone()).await?;
-
-    Ok((clusters_described, services_described, tasks))
+    let services_described = describe_services(&client, &services).await;
+    let tasks = get_tasks(&client, &services_described, max_items).await?;
+
+    let truncated = clusters.truncated
+        || services.values().any(|listing| listing.truncated)
+        || tasks.values().any(|listing| listing.truncated);
+    if truncated {
+        warn!("Stopped listing ecs resources at {} per listing, the response is truncated", max_items);
+    }
+    Ok(EcsQuery {
+        clusters_described,
+        services_described,
+        tasks: tasks.into_iter().map(|(service_arn, listing)| (service_arn, listing.arns)).collect(),
+        truncated,
+    })
 }
 
-pub fn build_ecs_client(client: Arc<HttpClient>, creds: Credentials) -> EcsClient {
-    let cred_provider = StaticProvider::new(
-        creds.aws_access_key,
-        creds.aws_secret_key,
-        Some(creds.aws_sts_token),
-        None,
-    );
-    EcsClient::new_with(client, cred_provider, Region::EuWest1) //TODO update region
+pub fn build_ecs_client(client: Arc<HttpClient>, creds: Credentials, region: Region) -> EcsClient {
+    let cred_provider = creds.build_provider();
+    EcsClient::new_with(client, cred_provider, region)
 }
 
-fn map_to_response(
-    clusters_described: DescribeClustersResponse,
-    services_described: HashMap<String, Vec<Service>>,
-    tasks: HashMap<String, ListTasksResponse>,
-) -> Result<ResponseWrapper, Error> {
+fn map_to_response(query: EcsQuery, region: &Region) -> Result<ResponseWrapper, Error> {
+    let EcsQuery { clusters_described, services_described, tasks, truncated } = query;
     let cluster_map: HashMap<String, Cluster> = build_cluster_map(clusters_described)?;
     let mut clusters: Vec<ClusterResponse> = cluster_map
         .keys()
@@ -111,6 +258,7 @@ fn map_to_response(
         .oks()
         .map(|(cluster, services)| {
             ClusterResponse {
+                region: region.name().to_owned(),
                 active_services_count: cluster.active_services_count,
                 cluster_arn: cluster.clone().cluster_arn,
                 cluster_name: cluster.clone().cluster_name,
@@ -122,7 +270,9 @@ fn map_to_response(
         .collect();
     clusters.sort_by(|a, b| a.cluster_name.cmp(&b.cluster_name));
     let response = ResponseWrapper {
-        clusters
+        clusters,
+        errors: vec![],
+        truncated,
     };
     Ok(response)
 }
@@ -143,7 +293,7 @@ fn build_cluster_map(clusters_described: DescribeClustersResponse) -> Result<Has
 
 fn iterate_services_described(
     services_described: &HashMap<String, Vec<Service>>,
-    tasks: &HashMap<String, ListTasksResponse>,
+    tasks: &HashMap<String, Vec<String>>,
     cluster_id: &str,
 ) -> Result<Vec<ServiceResponse>, Error> {
     let mut services: Vec<ServiceResponse> = services_described.get(cluster_id)
@@ -169,18 +319,18 @@ fn iterate_services_described(
                 service_arn: service.service_arn,
                 service_name: service.service_name,
                 task_definition: service.task_definition,
-                tasks: tasks.get(&service_arn).unwrap().task_arns.as_ref().unwrap().clone(),
+                tasks: tasks.get(&service_arn).cloned().unwrap_or_default(),
             }
         }).collect();
     services.sort_by(|a, b| a.service_name.cmp(&b.service_name));
     Ok(services)
 }
 
-pub async fn _iterate_clients(clients: &[EcsClient]) -> Result<Vec<ListClustersResponse>, Error> {
+pub async fn _iterate_clients(clients: &[EcsClient]) -> Result<Vec<Listing>, Error> {
     let clusters = clients
         .iter()
         .map(|client| async move {
-            get_clusters(&client).await
+            get_clusters(client, DEFAULT_MAX_LISTED).await
         });
     let joined_results = join_all(clusters).await
         .into_iter()
@@ -189,110 +339,142 @@ pub async fn _iterate_clients(clients: &[EcsClient]) -> Result<Vec<ListClustersR
     Ok(joined_results)
 }
 
-pub async fn get_clusters(client: &EcsClient) -> Result<ListClustersResponse, RusotoError<ListClustersError>> {
-    client.list_clusters(ListClustersRequest {
-        max_results: None,
-        next_token: None,
+pub async fn get_clusters(client: &EcsClient, max_items: usize) -> Result<Listing, RusotoError<ListClustersError>> {
+    paginate(max_items, |next_token| async move {
+        let response = client.list_clusters(ListClustersRequest {
+            max_results: Some(MAX_PAGE_SIZE),
+            next_token,
+        }).await?;
+        Ok((response.cluster_arns, response.next_token))
     }).await
 }
 
-pub async fn describe_clusters(client: &EcsClient, clusters: &Option<Vec<String>>) -> Result<DescribeClustersResponse, RusotoError<DescribeClustersError>> {
-    let owned_clusters = clusters.to_owned();
-    let request = DescribeClustersRequest {
-        clusters: owned_clusters,
-        include: None,
+/// Describes the clusters in batches of the most DescribeClusters takes at once
+pub async fn describe_clusters(client: &EcsClient, clusters: &[String]) -> Result<DescribeClustersResponse, RusotoError<DescribeClustersError>> {
+    let mut described = DescribeClustersResponse {
+        clusters: Some(Vec::new()),
+        failures: Some(Vec::new()),
     };
-    Ok(client.describe_clusters(request).await?)
+    for chunk in clusters.chunks(MAX_DESCRIBE_CLUSTERS) {
+        let response = client.describe_clusters(DescribeClustersRequest {
+            clusters: Some(chunk.to_vec()),
+            include: None,
+        }).await?;
+        described.clusters.get_or_insert_with(Vec::new).extend(response.clusters.unwrap_or_default());
+        described.failures.get_or_insert_with(Vec::new).extend(response.failures.unwrap_or_default());
+    }
+    Ok(described)
 }
 
-
-pub async fn get_services(client: &EcsClient, clusters: &Option<Vec<String>>) -> Result<HashMap<String, ListServicesResponse>, Error> {
-    let owned = clusters.to_owned().ok_or_else(|| anyhow!("Failed to take ownership of clusters"))?;
-    let services = owned
+pub async fn get_services(client: &EcsClient, clusters: &[String], max_items: usize) -> Result<HashMap<String, Listing>, Error> {
+    let services = clusters
         .iter()
         .map(|cluster| async move {
-            let cluster = cluster.to_owned();
-            (cluster.clone(), client.list_services(ListServicesRequest {
-                cluster: Some(cluster),
-                launch_type: None,
-                max_results: None,
-                next_token: None,
-                scheduling_strategy: None,
-            }).await.unwrap())
+            let listing = paginate(max_items, |next_token| async move {
+                let response = client.list_services(ListServicesRequest {
+                    cluster: Some(cluster.clone()),
+                    launch_type: None,
+                    max_results: Some(MAX_PAGE_SIZE),
+                    next_token,
+                    scheduling_strategy: None,
+                }).await?;
+                Ok((response.service_arns, response.next_token))
+            }).await;
+            listing
+                .map(|listing| (cluster.clone(), listing))
+                .map_err(|err| anyhow!(format!("Failed to list the services of {}: {}", cluster, match_rusoto_errors(err))))
         });
-    Ok(join_all(services).await
+    join_all(services).await
         .into_iter()
-        .collect())
+        .collect()
 }
 
-pub async fn get_tasks(client: &EcsClient, clusters: HashMap<String, Vec<Service>>) -> Result<HashMap<String, ListTasksResponse>, Error> {
+pub async fn get_tasks(client: &EcsClient, clusters: &HashMap<String, Vec<Service>>, max_items: usize) -> Result<HashMap<String, Listing>, Error> {
     let tasks = clusters.values().flatten()
-        .map(|service| async move {
-            let cluster = service.clone().cluster_arn.unwrap();
-            (service.service_arn.clone().unwrap(), client.list_tasks(ListTasksRequest {
-                cluster: Some(cluster),
-                container_instance: None,
-                desired_status: None,
-                family: None,
-                launch_type: None,
-                max_results: None,
-                next_token: None,
-                service_name: service.clone().service_name,
-                started_by: None,
-            }).await.unwrap())
+        .filter_map(|service| Some((service.cluster_arn.clone()?, service.service_arn.clone()?, service.service_name.clone())))
+        .map(|(cluster, service_arn, service_name)| async move {
+            let listing = paginate(max_items, |next_token| {
+                let cluster = cluster.clone();
+                let service_name = service_name.clone();
+                async move {
+                    let response = client.list_tasks(ListTasksRequest {
+                        cluster: Some(cluster),
+                        container_instance: None,
+                        desired_status: None,
+                        family: None,
+                        launch_type: None,
+                        max_results: Some(MAX_PAGE_SIZE),
+                        next_token,
+                        service_name,
+                        started_by: None,
+                    }).await?;
+                    Ok((response.task_arns, response.next_token))
+                }
+            }).await;
+            listing
+                .map(|listing| (service_arn.clone(), listing))
+                .map_err(|err| anyhow!(format!("Failed to list the tasks of {}: {}", service_arn, match_rusoto_errors(err))))
         });
-    Ok(join_all(tasks).await
+    join_all(tasks).await
         .into_iter()
-        .collect())
+        .collect()
 }
 
-pub async fn describe_services(client: &EcsClient, services: HashMap<String, ListServicesResponse>) -> HashMap<String, Vec<Service>> {
-    let clusters_service_arns = services.iter()
-        .filter(|(_, list_services)| !list_services.service_arns.as_ref().unwrap().is_empty())
-        .map(|(cluster, list_services)| {
-            let cluster = cluster.to_owned();
-
-            if let Some(service_arns) = list_services.clone().service_arns {
-                Ok((cluster, service_arns))
-            } else {
-                Err(anyhow!("Could not unwrap service arns"))
-            }
-        });
-
-    let clusters_services = clusters_service_arns
-        .oks()
-        .map(|(cluster, service_arns)| async move {
-            let cluster = cluster.to_owned();
-            let services = service_arns;
-
-            let result = client.describe_services(DescribeServicesRequest {
-                cluster: Some(cluster.clone()),
-                include: None,
-                services,
-            }).await;
-            if let Ok(services) = result {
-                Ok((cluster.clone(), services))
-            } else {
-                Err(anyhow!("Failed to describe services"))
+/// Describes each cluster's services in batches of the most DescribeServices takes at once, clusters that
+/// fail are left out
+pub async fn describe_services(client: &EcsClient, services: &HashMap<String, Listing>) -> HashMap<String, Vec<Service>> {
+    let clusters_services = services.iter()
+        .filter(|(_, listing)| !listing.arns.is_empty())
+        .map(|(cluster, listing)| async move {
+            let mut described: Vec<Service> = Vec::new();
+            for chunk in listing.arns.chunks(MAX_DESCRIBE_SERVICES) {
+                let response = client.describe_services(DescribeServicesRequest {
+                    cluster: Some(cluster.clone()),
+                    include: None,
+                    services: chunk.to_vec(),
+                }).await;
+                match response {
+                    Ok(response) => described.extend(response.services.unwrap_or_default()),
+                    Err(err) => {
+                        error!("Failed to describe the services of {}: {}", cluster, match_rusoto_errors(err));
+                        return Err(anyhow!("Failed to describe services"));
+                    }
+                }
             }
+            Ok((cluster.clone(), described))
         });
 
     join_all(clusters_services).await
         .into_iter()
         .oks()
-        .map(|(cluster_id, response)| {
-            if let Some(services) = response.services {
-                Ok((cluster_id, services))
-            } else {
-                Err(anyhow!("Failed to unwrap services from response"))
-            }
-        })
-        .oks()
         .collect()
 }
 
+/// Follows next_token until the listing is complete or holds `max_items` arns
+async fn paginate<F, Fut, E>(max_items: usize, mut list_page: F) -> Result<Listing, RusotoError<E>>
+    where F: FnMut(Option<String>) -> Fut,
+          Fut: Future<Output=Result<(Option<Vec<String>>, Option<String>), RusotoError<E>>>, {
+    let mut listing = Listing::default();
+    let mut next_token: Option<String> = None;
+    loop {
+        let (arns, token) = list_page(next_token).await?;
+        listing.arns.extend(arns.unwrap_or_default());
+        next_token = token.filter(|token| !token.is_empty());
+        if listing.arns.len() >= max_items {
+            listing.truncated = next_token.is_some() || listing.arns.len() > max_items;
+            listing.arns.truncate(max_items);
+            return Ok(listing);
+        }
+        if next_token.is_none() {
+            return Ok(listing);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_get_clusters() {}
 
@@ -355,4 +537,94 @@ mod tests {
 
     #[test]
     fn test_query_ecs_fail() {}
+
+    #[test]
+    fn test_merge_region_responses() {
+        let cluster = ClusterResponse {
+            region: "us-east-1".to_owned(),
+            cluster_name: Some("cluster".to_owned()),
+            ..Default::default()
+        };
+        let results = vec![
+            (Region::UsEast1, Ok(ResponseWrapper { clusters: vec![cluster.clone()], errors: vec![], truncated: true })),
+            (Region::ApSoutheast2, Err(anyhow!("Access denied"))),
+        ];
+        let response = merge_region_responses(results).unwrap();
+        assert_eq!(response.clusters, vec![cluster]);
+        assert!(response.truncated);
+        assert_eq!(response.errors, vec![RegionError {
+            region: "ap-southeast-2".to_owned(),
+            message: "Access denied".to_owned(),
+        }]);
+    }
+
+    #[test]
+    fn test_group_by_account() {
+        let cluster = ClusterResponse {
+            region: "us-east-1".to_owned(),
+            cluster_name: Some("cluster".to_owned()),
+            ..Default::default()
+        };
+        let results = vec![
+            ("arn:aws:iam::111111111111:role/read".to_owned(), Ok(ResponseWrapper { clusters: vec![cluster.clone()], errors: vec![], truncated: false })),
+            ("arn:aws:iam::111111111111:role/admin".to_owned(), Ok(ResponseWrapper { clusters: vec![], errors: vec![], truncated: true })),
+            ("arn:aws:iam::222222222222:role/read".to_owned(), Err(anyhow!("Access denied"))),
+        ];
+        let response = group_by_account(results);
+        assert_eq!(response.accounts, vec![AccountResponse {
+            account_id: "111111111111".to_owned(),
+            role_arns: vec!["arn:aws:iam::111111111111:role/read".to_owned(), "arn:aws:iam::111111111111:role/admin".to_owned()],
+            clusters: vec![cluster],
+            errors: vec![],
+            truncated: true,
+        }]);
+        assert_eq!(response.failures, vec![RoleFailure {
+            role_arn: "arn:aws:iam::222222222222:role/read".to_owned(),
+            message: "Access denied".to_owned(),
+        }]);
+    }
+
+    async fn paginate_pages(pages: usize, page_size: usize, max_items: usize) -> (Listing, usize) {
+        let mut requests = 0;
+        let listing = paginate(max_items, |next_token: Option<String>| {
+            requests += 1;
+            let page: usize = next_token.map(|token| token.parse().unwrap()).unwrap_or(0);
+            async move {
+                let arns = (0..page_size).map(|item| format!("arn-{}-{}", page, item)).collect();
+                let next_token = if page + 1 < pages { Some((page + 1).to_string()) } else { None };
+                Ok::<_, RusotoError<ListClustersError>>((Some(arns), next_token))
+            }
+        }).await.unwrap();
+        (listing, requests)
+    }
+
+    #[tokio::test]
+    async fn test_paginate() {
+        let (listing, requests) = paginate_pages(3, 2, 10).await;
+        assert_eq!(requests, 3);
+        assert_eq!(listing.arns.len(), 6);
+        assert_eq!(listing.arns.last().unwrap(), "arn-2-1");
+        assert!(!listing.truncated);
+
+        // Reaching the limit on the last page isn't truncation
+        let (listing, _) = paginate_pages(3, 2, 6).await;
+        assert_eq!(listing.arns.len(), 6);
+        assert!(!listing.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_truncated() {
+        let (listing, requests) = paginate_pages(5, 2, 3).await;
+        assert_eq!(requests, 2);
+        assert_eq!(listing.arns, vec!["arn-0-0", "arn-0-1", "arn-1-0"]);
+        assert!(listing.truncated);
+    }
+
+    #[test]
+    fn test_merge_region_responses_fail() {
+        let results = vec![
+            (Region::UsEast1, Err(anyhow!("Access denied"))),
+        ];
+        assert!(merge_region_responses(results).is_err());
+    }
 }
\ No newline at end of file
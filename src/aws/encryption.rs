@@ -0,0 +1,277 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::dto::UnlockRequest;
+use crate::aws::secret::SECRET_FIELDS;
+use crate::error::{CredentialError, ErrorWrapper};
+use crate::extract_rejection;
+use crate::state::AppState;
+
+/// Encrypted values are stored as `enc:v1:<base64 of nonce, tag and ciphertext>`
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+/// Encrypted with the key so a wrong passphrase is caught before any secret is read
+const CHECK_VALUE: &str = "tasky";
+const KEY_ITERATIONS: usize = 100_000;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Key derivation settings kept in ~/.awsManager.json once secrets are encrypted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionSettings {
+    pub salt: String,
+    pub iterations: usize,
+    pub check: String,
+}
+
+/// The passphrase along with the last key derived from it, deriving is slow on purpose so it is done once
+struct Unlocked {
+    passphrase: String,
+    key: Option<(String, usize, [u8; 32])>,
+}
+
+/// The passphrase secrets are encrypted with, shared by the server state that loads and persists the config and
+/// the unlock endpoint
+#[derive(Clone, Default)]
+pub struct Keyring {
+    unlocked: Arc<Mutex<Option<Unlocked>>>,
+}
+
+impl Keyring {
+    pub fn new(passphrase: Option<String>) -> Keyring {
+        let keyring = Keyring::default();
+        keyring.replace(passphrase);
+        keyring
+    }
+
+    /// Swaps the passphrase in while `check` runs, a wrong passphrase is swapped out again so a bad unlock
+    /// doesn't lock a server that was already unlocked
+    pub fn try_passphrase<T, F>(&self, passphrase: String, check: F) -> Result<T, Error>
+        where F: FnOnce() -> Result<T, Error>, {
+        let previous = self.replace(Some(passphrase));
+        let result = check();
+        if let Err(err) = &result {
+            if let Some(CredentialError::WrongPassphrase) = err.downcast_ref::<CredentialError>() {
+                *self.unlocked.lock().unwrap() = previous;
+            }
+        }
+        result
+    }
+
+    fn replace(&self, passphrase: Option<String>) -> Option<Unlocked> {
+        let unlocked = passphrase
+            .filter(|passphrase| !passphrase.is_empty())
+            .map(|passphrase| Unlocked { passphrase, key: None });
+        std::mem::replace(&mut *self.unlocked.lock().unwrap(), unlocked)
+    }
+
+    fn is_unlocked(&self) -> bool {
+        self.unlocked.lock().unwrap().is_some()
+    }
+
+    fn derive_key(&self, settings: &EncryptionSettings) -> Result<[u8; 32], Error> {
+        let mut unlocked = self.unlocked.lock().unwrap();
+        let unlocked = unlocked.as_mut().ok_or_else(|| anyhow!(CredentialError::ConfigLocked))?;
+        if let Some((salt, iterations, key)) = &unlocked.key {
+            if salt == &settings.salt && *iterations == settings.iterations {
+                return Ok(*key);
+            }
+        }
+
+        let salt = base64::decode(&settings.salt)?;
+        let mut key = [0u8; 32];
+        pbkdf2_hmac(unlocked.passphrase.as_bytes(), &salt, settings.iterations, MessageDigest::sha256(), &mut key)?;
+        unlocked.key = Some((settings.salt.clone(), settings.iterations, key));
+        Ok(key)
+    }
+}
+
+pub async fn unlock_filter(request: UnlockRequest, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let passphrase = request.passphrase.expose().to_owned();
+    // Loading checks the passphrase and encrypts a plain text file with it
+    extract_rejection!(state.keyring().try_passphrase(passphrase, || state.reload()))?;
+    Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT))
+}
+
+/// Encrypts the secret fields of a serialised config when a passphrase has been given, otherwise they stay
+/// plain text
+pub fn encrypt_secrets(keyring: &Keyring, config: &mut Value) -> Result<(), Error> {
+    if !keyring.is_unlocked() {
+        return Ok(());
+    }
+    let settings = match config.get("encryption").cloned().filter(|settings| !settings.is_null()) {
+        Some(settings) => serde_json::from_value::<EncryptionSettings>(settings)?,
+        None => {
+            let mut salt = [0u8; SALT_LENGTH];
+            rand_bytes(&mut salt)?;
+            EncryptionSettings {
+                salt: base64::encode(salt),
+                iterations: KEY_ITERATIONS,
+                check: String::new(),
+            }
+        }
+    };
+    let key = keyring.derive_key(&settings)?;
+    let settings = EncryptionSettings {
+        check: encrypt_value(&key, CHECK_VALUE)?,
+        ..settings
+    };
+
+    for field in SECRET_FIELDS.iter() {
+        if let Some(Value::String(secret)) = config.get(*field) {
+            if !secret.starts_with(ENCRYPTED_PREFIX) {
+                config[*field] = Value::String(encrypt_value(&key, secret)?);
+            }
+        }
+    }
+    config["encryption"] = serde_json::to_value(settings)?;
+    Ok(())
+}
+
+/// Decrypts the secret fields of a serialised config in place, returning true when plain text secrets were
+/// found while a passphrase is set so the caller can write them back encrypted
+pub fn decrypt_secrets(keyring: &Keyring, config: &mut Value) -> Result<bool, Error> {
+    let settings = match config.get("encryption").cloned().filter(|settings| !settings.is_null()) {
+        Some(settings) => Some(serde_json::from_value::<EncryptionSettings>(settings)?),
+        None => None,
+    };
+    let is_encrypted = |value: Option<&Value>| match value {
+        Some(Value::String(secret)) => secret.starts_with(ENCRYPTED_PREFIX),
+        _ => false,
+    };
+    let has_encrypted = SECRET_FIELDS.iter().any(|field| is_encrypted(config.get(*field)));
+    let has_plain = SECRET_FIELDS
+        .iter()
+        .any(|field| !is_encrypted(config.get(*field)) && config.get(*field).map(Value::is_string).unwrap_or(false));
+
+    // Without encrypted fields a passphrase is still checked against the settings so a wrong one is reported
+    if !has_encrypted && (settings.is_none() || !keyring.is_unlocked()) {
+        return Ok(keyring.is_unlocked() && has_plain);
+    }
+    let settings = settings
+        .ok_or_else(|| anyhow!("Secrets in awsManager.json are encrypted but the encryption settings are missing"))?;
+    if !keyring.is_unlocked() {
+        return Err(anyhow!(CredentialError::ConfigLocked));
+    }
+    let key = keyring.derive_key(&settings)?;
+    if decrypt_value(&key, &settings.check).ok().as_deref() != Some(CHECK_VALUE) {
+        return Err(anyhow!(CredentialError::WrongPassphrase));
+    }
+
+    for field in SECRET_FIELDS.iter() {
+        if let Some(Value::String(secret)) = config.get(*field) {
+            if secret.starts_with(ENCRYPTED_PREFIX) {
+                config[*field] = Value::String(decrypt_value(&key, secret)?);
+            }
+        }
+    }
+    Ok(has_plain)
+}
+
+fn encrypt_value(key: &[u8; 32], value: &str) -> Result<String, Error> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0u8; TAG_LENGTH];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &[], value.as_bytes(), &mut tag)?;
+
+    let mut data = Vec::with_capacity(NONCE_LENGTH + TAG_LENGTH + ciphertext.len());
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&tag);
+    data.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(&data)))
+}
+
+fn decrypt_value(key: &[u8; 32], value: &str) -> Result<String, Error> {
+    let data = base64::decode(value.trim_start_matches(ENCRYPTED_PREFIX))?;
+    if data.len() < NONCE_LENGTH + TAG_LENGTH {
+        return Err(anyhow!("Encrypted value in awsManager.json is too short"));
+    }
+    let (nonce, rest) = data.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+    let plaintext = decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &[], ciphertext, tag)
+        .map_err(|_| anyhow!("Could not decrypt a secret in awsManager.json"))?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn plain_config() -> Value {
+        json!({
+            "aws_access_key_id": "access",
+            "aws_secret_access_key": "secret",
+            "aws_temp_access_key_id": "temp-access",
+            "aws_temp_secret_access_key": null,
+            "aws_session_token": "token",
+            "region": "eu-west-1"
+        })
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let keyring = Keyring::new(Some("correct horse".to_owned()));
+        let mut config = plain_config();
+        assert!(decrypt_secrets(&keyring, &mut config.clone()).unwrap());
+        encrypt_secrets(&keyring, &mut config).unwrap();
+
+        let secret = config["aws_secret_access_key"].as_str().unwrap();
+        assert!(secret.starts_with(ENCRYPTED_PREFIX));
+        assert!(!serde_json::to_string(&config).unwrap().contains("secret\""));
+        assert_eq!(config["aws_access_key_id"], "access");
+        assert_eq!(config["aws_temp_secret_access_key"], Value::Null);
+
+        assert!(!decrypt_secrets(&keyring, &mut config).unwrap());
+        assert_eq!(config["aws_secret_access_key"], "secret");
+        assert_eq!(config["aws_session_token"], "token");
+    }
+
+    #[test]
+    fn test_decrypt_locked_and_wrong_passphrase() {
+        let mut config = plain_config();
+        encrypt_secrets(&Keyring::new(Some("correct horse".to_owned())), &mut config).unwrap();
+
+        let err = decrypt_secrets(&Keyring::new(None), &mut config.clone()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<CredentialError>(), Some(CredentialError::ConfigLocked)));
+
+        let err = decrypt_secrets(&Keyring::new(Some("battery staple".to_owned())), &mut config.clone()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<CredentialError>(), Some(CredentialError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn test_wrong_passphrase_keeps_unlocked() {
+        let keyring = Keyring::new(Some("correct horse".to_owned()));
+        let mut config = plain_config();
+        encrypt_secrets(&keyring, &mut config).unwrap();
+
+        let err = keyring
+            .try_passphrase("battery staple".to_owned(), || decrypt_secrets(&keyring, &mut config.clone()))
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<CredentialError>(), Some(CredentialError::WrongPassphrase)));
+        assert!(decrypt_secrets(&keyring, &mut config.clone()).is_ok());
+
+        let locked = Keyring::new(None);
+        assert!(locked.try_passphrase("correct horse".to_owned(), || decrypt_secrets(&locked, &mut config.clone())).is_ok());
+        assert!(decrypt_secrets(&locked, &mut config).is_ok());
+    }
+
+    #[test]
+    fn test_plain_config_without_passphrase() {
+        let keyring = Keyring::new(None);
+        let mut config = plain_config();
+        assert!(!decrypt_secrets(&keyring, &mut config).unwrap());
+        encrypt_secrets(&keyring, &mut config).unwrap();
+        assert_eq!(config, plain_config());
+    }
+}
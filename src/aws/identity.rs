@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use rusoto_core::Region;
+use rusoto_iam::{Iam, IamClient, ListAccountAliasesRequest};
+use rusoto_sts::{GetCallerIdentityRequest, Sts, StsClient};
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::cache::CredentialCache;
+use crate::aws::client;
+use crate::aws::client::HttpClient;
+use crate::aws::credentials::{build_role_or_base_credential, match_rusoto_errors, Credentials};
+use crate::aws::dto::{IdentityRequest, IdentityResponse};
+use crate::aws::profile::requested_region;
+use crate::aws::region::resolve_region;
+use crate::aws::roles::resolve_role_arn;
+use crate::error::ErrorWrapper;
+use crate::extract_rejection;
+use crate::state::AppState;
+
+/// Shows whose credentials a request would use, the base credentials or the role's when one is given
+pub async fn get_identity_filter(request: IdentityRequest, cache: CredentialCache, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(request.session.workspace.as_deref())))?;
+    let (role_arn, role_region) = extract_rejection!(resolve_role_arn(&request.role_arn, &request.role, &config))?;
+    let client = Arc::new(extract_rejection!(client::new_client())?);
+    let requested_region = requested_region(&request.region.clone().or(role_region), &request.session.profile, &config);
+    let region = extract_rejection!(resolve_region(&requested_region, &config))?;
+
+    let credentials =
+        extract_rejection!(build_role_or_base_credential(&role_arn, &request.session, &config, &client, &region, &cache).await)?;
+    let identity = extract_rejection!(get_identity(client, credentials, region).await)?;
+    Ok(warp::reply::json(&identity))
+}
+
+async fn get_identity(client: Arc<HttpClient>, credentials: Credentials, region: Region) -> Result<IdentityResponse, Error> {
+    let sts_client = StsClient::new_with(client.clone(), credentials.build_provider(), region.clone());
+    let caller = sts_client
+        .get_caller_identity(GetCallerIdentityRequest {})
+        .await
+        .map_err(match_rusoto_errors)?;
+
+    let iam_client = IamClient::new_with(client, credentials.build_provider(), iam_region(&region));
+    let account_alias = match iam_client.list_account_aliases(ListAccountAliasesRequest::default()).await {
+        Ok(response) => response.account_aliases.into_iter().next(),
+        Err(err) => {
+            // Plenty of roles aren't allowed to list aliases, the identity is still worth returning
+            debug!("Could not list the account aliases: {}", match_rusoto_errors(err));
+            None
+        }
+    };
+
+    Ok(IdentityResponse {
+        account: caller.account,
+        arn: caller.arn,
+        user_id: caller.user_id,
+        account_alias,
+        expiration: credentials.expiration,
+    })
+}
+
+/// Iam is a global service, its endpoint lives in the first region of each partition
+pub fn iam_region(region: &Region) -> Region {
+    let name = region.name();
+    if name.starts_with("cn-") {
+        Region::CnNorth1
+    } else if name.starts_with("us-gov-") {
+        Region::UsGovWest1
+    } else {
+        Region::UsEast1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iam_region() {
+        assert_eq!(iam_region(&Region::ApSoutheast2), Region::UsEast1);
+        assert_eq!(iam_region(&Region::CnNorthwest1), Region::CnNorth1);
+        assert_eq!(iam_region(&Region::UsGovEast1), Region::UsGovWest1);
+    }
+
+    #[tokio::test]
+    async fn test_identity_request_serialises() {
+        let request = warp::test::request()
+            .path("/identity?role=billing-prod&profile=workload&token_code=123456")
+            .filter(&warp::query::<IdentityRequest>())
+            .await
+            .unwrap();
+        assert_eq!(request.role, Some("billing-prod".to_owned()));
+        assert_eq!(request.session.profile, Some("workload".to_owned()));
+        assert_eq!(request.session.token_code, Some("123456".to_owned()));
+    }
+}
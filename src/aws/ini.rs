@@ -0,0 +1,228 @@
+use serde::Serialize;
+
+/// A problem found in an aws config or credentials file, pointing at the line that caused it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub section: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IniEntry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IniSection {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<IniEntry>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct IniFile {
+    pub sections: Vec<IniSection>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Parses the ini dialect of the aws shared config and credentials files.
+///
+/// Full line `#` and `;` comments are skipped, as are inline comments preceded by whitespace. Values may be
+/// quoted and indented lines below a key belong to that key, eg the nested `s3 =` settings, and are skipped.
+/// Malformed lines are reported as diagnostics and parsing carries on with the next line.
+pub fn parse_ini(contents: &str, file: &str) -> IniFile {
+    let mut ini = IniFile::default();
+    let mut in_nested_key = false;
+
+    for (index, raw_line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let diagnostic = |section: Option<&IniSection>, message: String| Diagnostic {
+            file: file.to_owned(),
+            line: line_number,
+            section: section.map(|section| section.name.clone()),
+            message,
+        };
+
+        let line = strip_inline_comment(raw_line.trim());
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if in_nested_key && raw_line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        in_nested_key = false;
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                ini.diagnostics.push(diagnostic(None, format!("Section header `{}` is missing a closing `]`", line)));
+                continue;
+            }
+            let name = line[1..line.len() - 1].trim();
+            if name.is_empty() {
+                ini.diagnostics.push(diagnostic(None, "Section header has no name".to_owned()));
+                continue;
+            }
+            if ini.sections.iter().any(|section| section.name == name) {
+                let message = format!("Section `{}` is defined again, its keys are merged into the first one", name);
+                ini.diagnostics.push(diagnostic(None, message));
+            }
+            ini.sections.push(IniSection {
+                name: name.to_owned(),
+                line: line_number,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let section = ini.sections.last_mut();
+        let index = match line.find('=') {
+            Some(index) => index,
+            None => {
+                let message = format!("Expected `key = value` but found `{}`", redact_line(line));
+                ini.diagnostics.push(diagnostic(section.as_deref(), message));
+                continue;
+            }
+        };
+        let key = line[..index].trim();
+        let value = line[index + 1..].trim();
+        let section = match section {
+            Some(section) => section,
+            None => {
+                ini.diagnostics.push(diagnostic(None, format!("Key `{}` is outside of a section", key)));
+                continue;
+            }
+        };
+        if key.is_empty() {
+            ini.diagnostics.push(diagnostic(Some(section), "Entry has no key".to_owned()));
+            continue;
+        }
+        let value = match unquote(value) {
+            Ok(value) => value,
+            Err(message) => {
+                ini.diagnostics.push(diagnostic(Some(section), format!("Value of `{}` {}", key, message)));
+                continue;
+            }
+        };
+        if section.entries.iter().any(|entry| entry.key == key) {
+            let message = format!("Key `{}` is set more than once, the last value is used", key);
+            ini.diagnostics.push(diagnostic(Some(section), message));
+        }
+        in_nested_key = value.is_empty();
+        section.entries.push(IniEntry {
+            key: key.to_owned(),
+            value,
+            line: line_number,
+        });
+    }
+    ini
+}
+
+/// Drops a trailing `# comment` or `; comment`, a `#` or `;` inside a value or quotes is kept
+fn strip_inline_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if (c == '"' || c == '\'') && (previous.is_whitespace() || previous == '=') => quote = Some(c),
+            None if (c == '#' || c == ';') && previous.is_whitespace() && index > 0 => return line[..index].trim_end(),
+            None => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Strips quotes wrapping the whole value, a value that only starts quoted like a command with arguments is kept
+fn unquote(value: &str) -> Result<String, &'static str> {
+    let quote = match value.chars().next() {
+        Some(first) if first == '"' || first == '\'' => first,
+        _ => return Ok(value.to_owned()),
+    };
+    match value[1..].find(quote) {
+        None => Err("has an unterminated quote"),
+        Some(index) if index + 2 == value.len() => Ok(value[1..value.len() - 1].to_owned()),
+        Some(_) => Ok(value.to_owned()),
+    }
+}
+
+/// Malformed lines can be a secret missing its `=`, only the start of them is shown
+fn redact_line(line: &str) -> String {
+    let shown: String = line.chars().take(4).collect();
+    if shown.len() < line.len() {
+        format!("{}...", shown)
+    } else {
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(section: &IniSection) -> Vec<(&str, &str)> {
+        section.entries.iter().map(|entry| (entry.key.as_str(), entry.value.as_str())).collect()
+    }
+
+    #[test]
+    fn test_parse_ini() {
+        let ini = parse_ini("# comment\n\
+            ; another comment\n\
+            [default]\n\
+            region=eu-west-1 # inline comment\n\
+            credential_process = /usr/bin/get creds --profile \"a b\"\n\
+            role_session_name = \"quoted # value\"\n\
+            s3 =\n\
+            \x20 max_concurrent_requests = 20\n\
+            \n\
+            [ profile other ]\n\
+            external_id = 'single;quoted'\n\
+            credential_process = \"/opt/get creds\" --json\n", "config");
+        assert!(ini.diagnostics.is_empty(), "{:?}", ini.diagnostics);
+        assert_eq!(ini.sections.len(), 2);
+        assert_eq!(values(&ini.sections[0]), vec![
+            ("region", "eu-west-1"),
+            ("credential_process", "/usr/bin/get creds --profile \"a b\""),
+            ("role_session_name", "quoted # value"),
+            ("s3", ""),
+        ]);
+        assert_eq!(ini.sections[1].name, "profile other");
+        assert_eq!(ini.sections[1].line, 10);
+        assert_eq!(values(&ini.sections[1]), vec![
+            ("external_id", "single;quoted"),
+            ("credential_process", "\"/opt/get creds\" --json"),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_ini_bracket_in_value() {
+        let ini = parse_ini("[default]\nsession_policy = {\"Statement\": [\"*\"]}\n", "config");
+        assert!(ini.diagnostics.is_empty());
+        assert_eq!(ini.sections.len(), 1);
+        assert_eq!(ini.sections[0].entries[0].value, "{\"Statement\": [\"*\"]}");
+    }
+
+    #[test]
+    fn test_parse_ini_diagnostics() {
+        let ini = parse_ini("orphan = value\n\
+            [default\n\
+            []\n\
+            [default]\n\
+            AKIASECRETVALUE\n\
+            = value\n\
+            region = \"eu-west-1\n\
+            region = eu-west-1\n\
+            region = eu-west-2\n", "credentials");
+        let lines: Vec<usize> = ini.diagnostics.iter().map(|diagnostic| diagnostic.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 5, 6, 7, 9]);
+        assert_eq!(ini.diagnostics[0].file, "credentials");
+        assert_eq!(ini.diagnostics[3].section, Some("default".to_owned()));
+        assert!(!ini.diagnostics[3].message.contains("SECRET"));
+        assert_eq!(values(&ini.sections[0]), vec![("region", "eu-west-1"), ("region", "eu-west-2")]);
+    }
+}
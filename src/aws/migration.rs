@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Error};
+use serde_json::{Map, Value};
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// Upgrades, in order, from the version given by their index to the next one
+const MIGRATIONS: [Migration; 1] = [add_required_fields];
+
+/// Schema version of ~/.awsManager.json written by this build
+pub const CONFIG_VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Brings a serialised config up to `CONFIG_VERSION`, returning the version it started at when anything
+/// was migrated. Files from before versioning have no `version` and count as version 0.
+pub fn migrate_config(config: &mut Value) -> Result<Option<u64>, Error> {
+    let fields = config
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("awsManager.json should hold a json object"))?;
+    let version = match fields.get("version") {
+        None | Some(Value::Null) => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow!(format!("version in awsManager.json should be a whole number, found {}", version)))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(anyhow!(format!(
+            "awsManager.json is version {} but this tasky-api only supports up to version {}, upgrade tasky-api to use it",
+            version, CONFIG_VERSION
+        )));
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    for migration in MIGRATIONS[version as usize..].iter() {
+        migration(fields);
+    }
+    fields.insert("version".to_owned(), Value::from(CONFIG_VERSION));
+    Ok(Some(version))
+}
+
+/// Version 1: the fields serde can't default are filled in so files written by hand or by older builds load
+fn add_required_fields(fields: &mut Map<String, Value>) {
+    for (field, default) in [
+        ("aws_access_key_id", Value::from("")),
+        ("aws_secret_access_key", Value::from("")),
+        ("aws_use_default_credentials", Value::from(false)),
+    ]
+    .iter()
+    {
+        if fields.get(*field).map(Value::is_null).unwrap_or(true) {
+            fields.insert((*field).to_owned(), default.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::aws::manager::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_unversioned_config() {
+        let mut config = json!({
+            "aws_use_default_credentials": true,
+            "region": "eu-west-1"
+        });
+        assert_eq!(migrate_config(&mut config).unwrap(), Some(0));
+        assert_eq!(config["version"], CONFIG_VERSION);
+        assert_eq!(config["aws_use_default_credentials"], true);
+
+        let config: Config = serde_json::from_value(config).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.aws_access_key_id, "");
+    }
+
+    #[test]
+    fn test_migrate_current_config() {
+        let mut config = serde_json::to_value(Config::init().unwrap()).unwrap();
+        let expected = config.clone();
+        assert_eq!(migrate_config(&mut config).unwrap(), None);
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn test_migrate_newer_config() {
+        let mut config = json!({ "version": CONFIG_VERSION + 1 });
+        let err = migrate_config(&mut config).unwrap_err();
+        assert!(format!("{}", err).contains("upgrade tasky-api"));
+        assert!(migrate_config(&mut json!({ "version": "1" })).is_err());
+    }
+}
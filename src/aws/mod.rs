@@ -1,7 +1,21 @@
+pub mod cache;
 pub mod cloudwatch_logs;
 pub mod ecs;
 pub mod s3;
+pub mod secret;
+pub mod session;
+pub mod sso;
+pub mod credential_source;
 pub mod credentials;
+pub mod credentials_file;
+pub mod encryption;
+pub mod identity;
+pub mod ini;
 pub mod manager;
+pub mod migration;
+pub mod permissions;
+pub mod profile;
 pub mod client;
 pub mod dto;
+pub mod region;
+pub mod roles;
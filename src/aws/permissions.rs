@@ -0,0 +1,406 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use futures::future::join_all;
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::{Region, RusotoError};
+use rusoto_ecs::{
+    DescribeClustersRequest, DescribeServicesRequest, Ecs, EcsClient, ListClustersRequest, ListServicesRequest,
+    ListTasksRequest,
+};
+use rusoto_iam::{EvaluationResult, Iam, IamClient, ListAccountAliasesRequest, SimulatePrincipalPolicyRequest};
+use rusoto_logs::{CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsRequest};
+use rusoto_s3::{HeadObjectRequest, S3, S3Client};
+use rusoto_sts::{GetCallerIdentityRequest, Sts, StsClient};
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::cache::CredentialCache;
+use crate::aws::client;
+use crate::aws::client::HttpClient;
+use crate::aws::cloudwatch_logs::build_logs_client;
+use crate::aws::credentials::{build_role_or_base_credential, match_rusoto_errors, Credentials};
+use crate::aws::dto::{ActionPermission, CheckMethod, Decision, PermissionsRequest, PermissionsResponse};
+use crate::aws::ecs::build_ecs_client;
+use crate::aws::identity::iam_region;
+use crate::aws::profile::requested_region;
+use crate::aws::region::resolve_region;
+use crate::aws::roles::resolve_role_arn;
+use crate::aws::s3::build_s3_client;
+use crate::aws::secret::redact_tokens;
+use crate::error::ErrorWrapper;
+use crate::extract_rejection;
+use crate::state::AppState;
+
+/// Actions tasky calls, checked by the preflight
+pub const TASKY_ACTIONS: [&str; 8] = [
+    "ecs:ListClusters",
+    "ecs:DescribeClusters",
+    "ecs:ListServices",
+    "ecs:DescribeServices",
+    "ecs:ListTasks",
+    "logs:FilterLogEvents",
+    "s3:GetObject",
+    "iam:ListAccountAliases",
+];
+/// Probes name resources that shouldn't exist, a not found error still shows the call was authorised
+const PROBE_RESOURCE: &str = "tasky-preflight";
+
+/// Checks which of the actions tasky uses the base credentials or a role are allowed to call
+pub async fn get_permissions_filter(request: PermissionsRequest, cache: CredentialCache, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config().and_then(|config| config.for_workspace(request.session.workspace.as_deref())))?;
+    let (role_arn, role_region) = extract_rejection!(resolve_role_arn(&request.role_arn, &request.role, &config))?;
+    let client = Arc::new(extract_rejection!(client::new_client())?);
+    let requested_region = requested_region(&request.region.clone().or(role_region), &request.session.profile, &config);
+    let region = extract_rejection!(resolve_region(&requested_region, &config))?;
+
+    let credentials =
+        extract_rejection!(build_role_or_base_credential(&role_arn, &request.session, &config, &client, &region, &cache).await)?;
+    let principal_arn = if role_arn.is_empty() {
+        extract_rejection!(get_principal_arn(client.clone(), &credentials, &region).await)?
+    } else {
+        Some(role_arn)
+    };
+
+    let simulation = match &principal_arn {
+        Some(principal_arn) => simulate_actions(client.clone(), &credentials, &region, principal_arn).await,
+        None => Err(anyhow!("The caller has no arn to simulate")),
+    };
+    let response = match simulation {
+        Ok(actions) => PermissionsResponse {
+            principal_arn,
+            method: CheckMethod::Simulation,
+            simulation_error: None,
+            actions,
+        },
+        Err(err) => {
+            let simulation_error = redact_tokens(&format!("{}", err));
+            debug!("Falling back to probes, could not simulate the principal policy: {}", simulation_error);
+            PermissionsResponse {
+                principal_arn,
+                method: CheckMethod::Probe,
+                simulation_error: Some(simulation_error),
+                actions: probe_actions(client, credentials, region, &request).await,
+            }
+        }
+    };
+    Ok(warp::reply::json(&response))
+}
+
+async fn get_principal_arn(client: Arc<HttpClient>, credentials: &Credentials, region: &Region) -> Result<Option<String>, Error> {
+    let sts_client = StsClient::new_with(client, credentials.build_provider(), region.clone());
+    let caller = sts_client
+        .get_caller_identity(GetCallerIdentityRequest {})
+        .await
+        .map_err(match_rusoto_errors)?;
+    Ok(caller.arn.map(|arn| build_principal_arn(&arn)))
+}
+
+/// Policies can't be simulated for an assumed role session, only for the role behind it
+fn build_principal_arn(caller_arn: &str) -> String {
+    let parts: Vec<&str> = caller_arn.splitn(6, ':').collect();
+    if parts.len() == 6 && parts[2] == "sts" && parts[5].starts_with("assumed-role/") {
+        let role_name = parts[5].split('/').nth(1).unwrap_or_default();
+        return format!("{}:{}:iam::{}:role/{}", parts[0], parts[1], parts[4], role_name);
+    }
+    caller_arn.to_owned()
+}
+
+async fn simulate_actions(client: Arc<HttpClient>, credentials: &Credentials, region: &Region, principal_arn: &str) -> Result<Vec<ActionPermission>, Error> {
+    let iam_client = IamClient::new_with(client, credentials.build_provider(), iam_region(region));
+    let mut results: Vec<EvaluationResult> = Vec::new();
+    let mut marker: Option<String> = None;
+    loop {
+        let response = iam_client
+            .simulate_principal_policy(SimulatePrincipalPolicyRequest {
+                action_names: TASKY_ACTIONS.iter().map(|action| action.to_string()).collect(),
+                policy_source_arn: principal_arn.to_owned(),
+                marker: marker.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(match_rusoto_errors)?;
+        results.extend(response.evaluation_results.unwrap_or_default());
+        let is_truncated = response.is_truncated.unwrap_or(false);
+        marker = response.marker.filter(|_| is_truncated);
+        if marker.is_none() {
+            break;
+        }
+    }
+    Ok(TASKY_ACTIONS
+        .iter()
+        .map(|action| match results.iter().find(|result| result.eval_action_name.eq_ignore_ascii_case(action)) {
+            Some(result) => build_simulated_permission(action, result),
+            None => ActionPermission {
+                action: action.to_string(),
+                decision: Decision::Unknown,
+                detail: Some("Missing from the simulation results".to_owned()),
+            },
+        })
+        .collect())
+}
+
+fn build_simulated_permission(action: &str, result: &EvaluationResult) -> ActionPermission {
+    let decision = if result.eval_decision == "allowed" { Decision::Allowed } else { Decision::Denied };
+    let detail = match &result.missing_context_values {
+        Some(missing) if !missing.is_empty() => Some(format!("{}, missing context {}", result.eval_decision, missing.join(", "))),
+        _ if decision == Decision::Denied => Some(result.eval_decision.clone()),
+        _ => None,
+    };
+    ActionPermission {
+        action: action.to_owned(),
+        decision,
+        detail,
+    }
+}
+
+/// Clients for the probes, built once and shared by every action
+struct ProbeClients {
+    ecs: EcsClient,
+    logs: CloudWatchLogsClient,
+    s3: S3Client,
+    iam: IamClient,
+}
+
+async fn probe_actions(client: Arc<HttpClient>, credentials: Credentials, region: Region, request: &PermissionsRequest) -> Vec<ActionPermission> {
+    let clients = ProbeClients {
+        ecs: build_ecs_client(client.clone(), credentials.clone(), region.clone()),
+        logs: build_logs_client(client.clone(), credentials.clone(), region.clone()),
+        s3: build_s3_client(client.clone(), credentials.clone(), region.clone()),
+        iam: IamClient::new_with(client, credentials.build_provider(), iam_region(&region)),
+    };
+    let probes = TASKY_ACTIONS.iter().map(|action| {
+        let clients = &clients;
+        async move {
+            let (decision, detail) = probe_action(action, clients, request).await;
+            ActionPermission {
+                action: action.to_string(),
+                decision,
+                detail: detail.map(|detail| redact_tokens(&detail)),
+            }
+        }
+    });
+    join_all(probes).await
+}
+
+/// The cheapest call that needs the action, against the default cluster or a resource that doesn't exist
+async fn probe_action(action: &str, clients: &ProbeClients, request: &PermissionsRequest) -> (Decision, Option<String>) {
+    match action {
+        "ecs:ListClusters" => probe_decision(clients.ecs.list_clusters(ListClustersRequest {
+            max_results: Some(1),
+            ..Default::default()
+        }).await),
+        "ecs:DescribeClusters" => probe_decision(clients.ecs.describe_clusters(DescribeClustersRequest::default()).await),
+        "ecs:ListServices" => probe_decision(clients.ecs.list_services(ListServicesRequest {
+            max_results: Some(1),
+            ..Default::default()
+        }).await),
+        "ecs:DescribeServices" => probe_decision(clients.ecs.describe_services(DescribeServicesRequest {
+            services: vec![PROBE_RESOURCE.to_owned()],
+            ..Default::default()
+        }).await),
+        "ecs:ListTasks" => probe_decision(clients.ecs.list_tasks(ListTasksRequest {
+            max_results: Some(1),
+            ..Default::default()
+        }).await),
+        "logs:FilterLogEvents" => probe_decision(clients.logs.filter_log_events(FilterLogEventsRequest {
+            log_group_name: request.log_group.clone().unwrap_or_else(|| PROBE_RESOURCE.to_owned()),
+            limit: Some(1),
+            ..Default::default()
+        }).await),
+        "s3:GetObject" => match request.bucket_file.as_ref().and_then(|bucket_file| split_bucket_file(bucket_file)) {
+            // HeadObject is authorised by s3:GetObject without downloading anything
+            Some((bucket, key)) => probe_decision(clients.s3.head_object(HeadObjectRequest {
+                bucket,
+                key,
+                ..Default::default()
+            }).await),
+            None => (Decision::Unknown, Some("Give a bucket_file to probe s3:GetObject".to_owned())),
+        },
+        "iam:ListAccountAliases" => probe_decision(clients.iam.list_account_aliases(ListAccountAliasesRequest {
+            max_items: Some(1),
+            ..Default::default()
+        }).await),
+        _ => (Decision::Unknown, Some(format!("No probe for {}", action))),
+    }
+}
+
+fn split_bucket_file(bucket_file: &str) -> Option<(String, String)> {
+    let mut parts = bucket_file.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(bucket), Some(key)) if !bucket.is_empty() && !key.is_empty() => Some((bucket.to_owned(), key.to_owned())),
+        _ => None,
+    }
+}
+
+/// A probe names resources that don't exist, so only a resource missing answer shows the call got past
+/// authorisation. Throttling, bad credentials and other failures say nothing either way.
+fn probe_decision<T, E: std::error::Error + 'static>(result: Result<T, RusotoError<E>>) -> (Decision, Option<String>) {
+    match result {
+        Ok(_) => (Decision::Allowed, None),
+        Err(RusotoError::Unknown(response)) => unknown_response_decision(&response),
+        Err(RusotoError::Service(err)) => {
+            // Typed errors only show their code, eg ClusterNotFound, in their debug output
+            let code = format!("{:?}", err);
+            let message = format!("{}", err);
+            let decision = if is_credential_error(&message) {
+                Decision::Unknown
+            } else if is_access_denied(&code) || is_access_denied(&message) {
+                Decision::Denied
+            } else if is_resource_missing(&code) {
+                Decision::Allowed
+            } else {
+                Decision::Unknown
+            };
+            (decision, Some(message))
+        }
+        Err(err) => (Decision::Unknown, Some(format!("{}", match_rusoto_errors(err)))),
+    }
+}
+
+/// Errors rusoto has no type for, access denied among them for the json services and every s3 HeadObject error
+fn unknown_response_decision(response: &BufferedHttpResponse) -> (Decision, Option<String>) {
+    let body = String::from_utf8_lossy(&response.body).to_string();
+    let message = if body.is_empty() {
+        format!("Aws returned {}", response.status)
+    } else {
+        format!("Aws returned {}: {}", response.status, body)
+    };
+    let status = response.status.as_u16();
+    // Bad credentials also come back as 403, check for them before counting a 403 as denied
+    let decision = if is_credential_error(&body) {
+        Decision::Unknown
+    } else if status == 403 || is_access_denied(&body) {
+        Decision::Denied
+    } else if status == 404 || is_resource_missing(&body) {
+        Decision::Allowed
+    } else {
+        Decision::Unknown
+    };
+    (decision, Some(message))
+}
+
+fn is_access_denied(message: &str) -> bool {
+    ["AccessDenied", "not authorized", "UnauthorizedOperation"]
+        .iter()
+        .any(|denied| message.contains(denied))
+}
+
+/// Errors the probes expect for their made up resources
+fn is_resource_missing(message: &str) -> bool {
+    ["ResourceNotFound", "ClusterNotFound", "ServiceNotFound", "NoSuchKey"]
+        .iter()
+        .any(|missing| message.contains(missing))
+}
+
+/// The credentials were rejected before any policy was looked at
+fn is_credential_error(message: &str) -> bool {
+    [
+        "UnrecognizedClient",
+        "InvalidClientTokenId",
+        "ExpiredToken",
+        "SignatureDoesNotMatch",
+        "InvalidAccessKeyId",
+        "IncompleteSignature",
+    ]
+        .iter()
+        .any(|invalid| message.contains(invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use rusoto_ecs::DescribeServicesError;
+
+    use super::*;
+
+    fn build_response(status: u16, body: &str) -> BufferedHttpResponse {
+        BufferedHttpResponse {
+            status: hyper::StatusCode::from_u16(status).unwrap(),
+            body: bytes::Bytes::from(body.to_owned()),
+            headers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_build_principal_arn() {
+        assert_eq!(
+            build_principal_arn("arn:aws:sts::123456789012:assumed-role/tasky-read-only/tasky-user"),
+            "arn:aws:iam::123456789012:role/tasky-read-only"
+        );
+        assert_eq!(build_principal_arn("arn:aws:iam::123456789012:user/dev"), "arn:aws:iam::123456789012:user/dev");
+    }
+
+    #[test]
+    fn test_build_simulated_permission() {
+        let result = EvaluationResult {
+            eval_action_name: "ecs:ListClusters".to_owned(),
+            eval_decision: "implicitDeny".to_owned(),
+            ..Default::default()
+        };
+        let permission = build_simulated_permission("ecs:ListClusters", &result);
+        assert_eq!(permission.decision, Decision::Denied);
+        assert_eq!(permission.detail, Some("implicitDeny".to_owned()));
+
+        let result = EvaluationResult {
+            eval_decision: "allowed".to_owned(),
+            ..result
+        };
+        assert_eq!(build_simulated_permission("ecs:ListClusters", &result).decision, Decision::Allowed);
+    }
+
+    #[test]
+    fn test_probe_decision() {
+        let denied = r#"{"__type":"AccessDeniedException","Message":"User is not authorized to perform: ecs:ListClusters"}"#;
+        let not_found = r#"{"__type":"ResourceNotFoundException","message":"The specified log group does not exist."}"#;
+        let decision = |status, body| {
+            let result: Result<(), RusotoError<fmt::Error>> = Err(RusotoError::Unknown(build_response(status, body)));
+            probe_decision(result).0
+        };
+        assert_eq!(decision(400, denied), Decision::Denied);
+        assert_eq!(decision(403, ""), Decision::Denied);
+        assert_eq!(decision(400, not_found), Decision::Allowed);
+        assert_eq!(decision(404, ""), Decision::Allowed);
+        assert_eq!(decision(503, ""), Decision::Unknown);
+        assert_eq!(probe_decision::<(), fmt::Error>(Ok(())).0, Decision::Allowed);
+    }
+
+    #[test]
+    fn test_probe_decision_unknown() {
+        let throttled = r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#;
+        let expired = r#"{"__type":"ExpiredTokenException","message":"The security token included in the request is expired"}"#;
+        let unrecognised = r#"{"__type":"UnrecognizedClientException","message":"The security token included in the request is invalid."}"#;
+        let invalid_token = "<ErrorResponse><Error><Code>InvalidClientTokenId</Code></Error></ErrorResponse>";
+        let invalid = r#"{"__type":"ValidationException","message":"1 validation error detected"}"#;
+        let decision = |status, body| {
+            let result: Result<(), RusotoError<fmt::Error>> = Err(RusotoError::Unknown(build_response(status, body)));
+            probe_decision(result).0
+        };
+        assert_eq!(decision(400, throttled), Decision::Unknown);
+        assert_eq!(decision(400, expired), Decision::Unknown);
+        assert_eq!(decision(400, unrecognised), Decision::Unknown);
+        assert_eq!(decision(403, invalid_token), Decision::Unknown);
+        assert_eq!(decision(400, invalid), Decision::Unknown);
+    }
+
+    #[test]
+    fn test_probe_decision_service_error() {
+        let not_found: Result<(), RusotoError<DescribeServicesError>> =
+            Err(RusotoError::Service(DescribeServicesError::ClusterNotFound("Cluster not found.".to_owned())));
+        assert_eq!(probe_decision(not_found).0, Decision::Allowed);
+        let denied: Result<(), RusotoError<DescribeServicesError>> = Err(RusotoError::Service(DescribeServicesError::Client(
+            "User: arn:aws:sts::123456789012:assumed-role/tasky/dev is not authorized to perform: ecs:DescribeServices".to_owned(),
+        )));
+        assert_eq!(probe_decision(denied).0, Decision::Denied);
+        let invalid: Result<(), RusotoError<DescribeServicesError>> =
+            Err(RusotoError::Service(DescribeServicesError::InvalidParameter("Invalid identifier".to_owned())));
+        assert_eq!(probe_decision(invalid).0, Decision::Unknown);
+    }
+
+    #[test]
+    fn test_split_bucket_file() {
+        assert_eq!(split_bucket_file("tasky-config:env/dev.json"), Some(("tasky-config".to_owned(), "env/dev.json".to_owned())));
+        assert_eq!(split_bucket_file("tasky-config"), None);
+    }
+}
@@ -0,0 +1,674 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Error};
+use rusoto_core::Region;
+use rusoto_sts::AssumeRoleRequest;
+use serde::Deserialize;
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::cache::{CacheKey, CacheSource, CredentialCache};
+use crate::aws::client::HttpClient;
+use crate::aws::credentials::{
+    assume_with_provider, default_session_name, parse_expiration, validate_duration_seconds, validate_session_name, Credentials,
+};
+use crate::aws::dto::{ProfileKind, ProfileSummary, ProfilesResponse, SessionOptions};
+use crate::aws::ini::{parse_ini, Diagnostic};
+use crate::aws::manager::Config;
+use crate::aws::secret::Secret;
+use crate::aws::sso::{is_sso_profile, sso_credentials};
+use crate::error::ErrorWrapper;
+use crate::extract_rejection;
+
+/// A profile merged from `~/.aws/config` and `~/.aws/credentials`, credentials file values win
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub aws_access_key_id: Option<String>,
+    pub aws_secret_access_key: Option<Secret>,
+    pub aws_session_token: Option<Secret>,
+    pub region: Option<String>,
+    pub role_arn: Option<String>,
+    pub source_profile: Option<String>,
+    pub role_session_name: Option<String>,
+    pub external_id: Option<String>,
+    pub mfa_serial: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub credential_process: Option<String>,
+    pub sso_session: Option<String>,
+    pub sso_start_url: Option<String>,
+    pub sso_region: Option<String>,
+    pub sso_account_id: Option<String>,
+    pub sso_role_name: Option<String>,
+}
+
+pub type Profiles = BTreeMap<String, Profile>;
+
+type Sections = BTreeMap<String, HashMap<String, Option<String>>>;
+
+/// A parsed config or credentials file along with the line each profile's section starts on
+#[derive(Default)]
+struct ProfileFile {
+    file: String,
+    sections: Sections,
+    lines: BTreeMap<String, usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+const SSO_SESSION_PREFIX: &str = "sso-session ";
+
+/// Output of a `credential_process`, see https://docs.aws.amazon.com/cli/latest/topic/config-vars.html#sourcing-credentials-from-external-processes
+#[derive(Debug, Deserialize)]
+struct ProcessCredentials {
+    #[serde(rename = "Version")]
+    version: i64,
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: String,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: String,
+    #[serde(rename = "SessionToken")]
+    session_token: Option<String>,
+    #[serde(rename = "Expiration")]
+    expiration: Option<String>,
+}
+
+pub fn load_profiles() -> Result<Profiles, Error> {
+    let credentials_path = build_credentials_path()?;
+    let config_path = build_aws_config_path();
+    debug!("Loading profiles from {:?} and {:?}", credentials_path, config_path);
+
+    let credentials_sections = parse_profile_file(&credentials_path, false)?;
+    let config_sections = match config_path {
+        Some(config_path) => parse_profile_file(&config_path, true)?,
+        None => Sections::new(),
+    };
+    Ok(merge_sections(config_sections, credentials_sections))
+}
+
+pub async fn get_profiles_filter() -> Result<impl warp::Reply, Rejection> {
+    let profiles = extract_rejection!(list_profiles())?;
+    Ok(warp::reply::json(&profiles))
+}
+
+/// Every profile in the config and credentials files along with the problems found in them
+pub fn list_profiles() -> Result<ProfilesResponse, Error> {
+    let config_file = match build_aws_config_path() {
+        Some(config_path) => Some(read_profile_file(&config_path, true)?),
+        None => None,
+    };
+    let credentials_file = match build_credentials_path() {
+        Ok(credentials_path) => Some(read_profile_file(&credentials_path, false)?),
+        Err(_) => None,
+    };
+    Ok(summarise_profiles(config_file, credentials_file))
+}
+
+fn summarise_profiles(config_file: Option<ProfileFile>, credentials_file: Option<ProfileFile>) -> ProfilesResponse {
+    let mut locations: BTreeMap<String, (String, usize)> = BTreeMap::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut sections: Vec<Sections> = Vec::new();
+    for profile_file in std::iter::once(config_file).chain(std::iter::once(credentials_file)) {
+        let profile_file = profile_file.unwrap_or_default();
+        for (name, line) in profile_file.lines {
+            locations.insert(name, (profile_file.file.clone(), line));
+        }
+        diagnostics.extend(profile_file.diagnostics);
+        sections.push(profile_file.sections);
+    }
+    let credentials_sections = sections.pop().unwrap_or_default();
+    let profiles = merge_sections(sections.pop().unwrap_or_default(), credentials_sections);
+
+    let (profile_diagnostics, problems): (Vec<Diagnostic>, Vec<Diagnostic>) = diagnostics
+        .into_iter()
+        .partition(|diagnostic| diagnostic.section.as_ref().map(|name| profiles.contains_key(name)).unwrap_or(false));
+    let profiles = profiles
+        .values()
+        .map(|profile| {
+            let (file, line) = locations.get(&profile.name).cloned().unwrap_or_default();
+            let mut profile_problems: Vec<Diagnostic> = profile_diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.section.as_ref() == Some(&profile.name))
+                .cloned()
+                .collect();
+            profile_problems.extend(validate_profile(profile, &profiles).into_iter().map(|message| Diagnostic {
+                file: file.clone(),
+                line,
+                section: Some(profile.name.clone()),
+                message,
+            }));
+            ProfileSummary {
+                name: profile.name.clone(),
+                kind: profile_kind(profile),
+                region: profile.region.clone(),
+                role_arn: profile.role_arn.clone(),
+                source_profile: profile.source_profile.clone(),
+                mfa_serial: profile.mfa_serial.clone(),
+                sso_account_id: profile.sso_account_id.clone(),
+                sso_role_name: profile.sso_role_name.clone(),
+                has_session_token: profile.aws_session_token.is_some(),
+                problems: profile_problems,
+            }
+        })
+        .collect();
+    ProfilesResponse { profiles, problems }
+}
+
+fn profile_kind(profile: &Profile) -> ProfileKind {
+    if profile.role_arn.is_some() {
+        ProfileKind::AssumeRole
+    } else if is_sso_profile(profile) {
+        ProfileKind::Sso
+    } else if profile.aws_access_key_id.is_some() || profile.aws_secret_access_key.is_some() {
+        ProfileKind::Static
+    } else if profile.credential_process.is_some() {
+        ProfileKind::CredentialProcess
+    } else {
+        ProfileKind::None
+    }
+}
+
+/// Problems that stop the profile from resolving to credentials
+fn validate_profile(profile: &Profile, profiles: &Profiles) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    match (&profile.aws_access_key_id, &profile.aws_secret_access_key) {
+        (Some(_), None) => problems.push("aws_access_key_id is set without aws_secret_access_key".to_owned()),
+        (None, Some(_)) => problems.push("aws_secret_access_key is set without aws_access_key_id".to_owned()),
+        _ => {}
+    }
+    if profile.aws_session_token.is_some() && profile.aws_access_key_id.is_none() {
+        problems.push("aws_session_token is set without aws_access_key_id".to_owned());
+    }
+    if profile.source_profile.is_some() && profile.role_arn.is_none() {
+        problems.push("source_profile is set without a role_arn".to_owned());
+    }
+    if profile.role_arn.is_some() {
+        match build_profile_chain(&profile.name, profiles) {
+            Ok((source, _)) if !has_credentials(source) => {
+                problems.push(format!("Source profile {} has no credentials", source.name))
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(format!("{}", err)),
+        }
+    }
+    if is_sso_profile(profile) {
+        let sso_values = [
+            ("sso_start_url", &profile.sso_start_url),
+            ("sso_region", &profile.sso_region),
+            ("sso_account_id", &profile.sso_account_id),
+            ("sso_role_name", &profile.sso_role_name),
+        ];
+        for (key, value) in sso_values.iter() {
+            if value.is_none() {
+                problems.push(format!("Sso profile is missing {}", key));
+            }
+        }
+    }
+    problems
+}
+
+fn has_credentials(profile: &Profile) -> bool {
+    (profile.aws_access_key_id.is_some() && profile.aws_secret_access_key.is_some())
+        || is_sso_profile(profile)
+        || profile.credential_process.is_some()
+}
+
+/// The region a request asked for, falling back to the region of the profile it picked
+pub fn requested_region(region: &Option<String>, profile: &Option<String>, config: &Config) -> Option<String> {
+    region.clone().or_else(|| {
+        let profile = config.profiles.get(profile.as_ref()?)?;
+        profile.region.clone()
+    })
+}
+
+/// Resolves a profile to credentials, assuming each `role_arn` along its `source_profile` chain
+pub async fn resolve_profile(
+    name: &str,
+    profiles: &Profiles,
+    client: Arc<HttpClient>,
+    session: &SessionOptions,
+    region: &Region,
+    cache: &CredentialCache,
+) -> Result<Credentials, Error> {
+    let (source, roles) = build_profile_chain(name, profiles)?;
+
+    let mut credentials = cache
+        .get_or_fetch_source(CacheSource::Profile(source.name.clone()), || build_source_credentials(source))
+        .await?;
+    for profile in roles {
+        let assume_role_request = build_profile_assume_role_request(profile, session)?;
+        let source_credentials = credentials.clone();
+        // Profile roles only depend on the aws files, so every workspace shares them. Each hop is cached under its
+        // own profile, profiles with the same role_arn can have different source profiles.
+        let cache_key = CacheKey::new(&assume_role_request, region, CacheSource::Profile(profile.name.clone()), None);
+        credentials = assume_with_provider(client.clone(), assume_role_request, region, cache, cache_key, || {
+            Ok(source_credentials.build_provider())
+        })
+            .await
+            .with_context(|| format!("Failed assuming the role for profile {}", profile.name))?;
+    }
+    Ok(credentials)
+}
+
+/// Walks `source_profile` until it finds the profile holding the credentials, returning it and the role
+/// profiles to assume from it in order.
+fn build_profile_chain<'a>(name: &str, profiles: &'a Profiles) -> Result<(&'a Profile, Vec<&'a Profile>), Error> {
+    let mut roles: Vec<&Profile> = Vec::new();
+    let mut current = find_profile(name, profiles)?;
+    while current.role_arn.is_some() {
+        if roles.iter().any(|role| role.name == current.name) {
+            return Err(anyhow!(format!("Profile {} has a source_profile loop", name)));
+        }
+        roles.push(current);
+        let source = current.source_profile
+            .as_ref()
+            .ok_or_else(|| anyhow!(format!("Profile {} has a role_arn but no source_profile", current.name)))?;
+        if source == &current.name {
+            // The credentials live alongside the role_arn
+            break;
+        }
+        current = find_profile(source, profiles)?;
+    }
+    roles.reverse();
+    Ok((current, roles))
+}
+
+fn find_profile<'a>(name: &str, profiles: &'a Profiles) -> Result<&'a Profile, Error> {
+    profiles
+        .get(name)
+        .ok_or_else(|| anyhow!(format!("No aws profile named {}", name)))
+}
+
+async fn build_source_credentials(profile: &Profile) -> Result<Credentials, Error> {
+    if let (Some(access_key), Some(secret_key)) = (&profile.aws_access_key_id, &profile.aws_secret_access_key) {
+        return Ok(Credentials {
+            aws_access_key: access_key.clone(),
+            aws_secret_key: secret_key.clone(),
+            aws_sts_token: profile.aws_session_token.clone(),
+            expiration: None,
+        });
+    }
+    if is_sso_profile(profile) {
+        return sso_credentials(profile).await;
+    }
+    match &profile.credential_process {
+        Some(command) => run_credential_process(command).await
+            .with_context(|| format!("credential_process failed for profile {}", profile.name)),
+        None => Err(anyhow!(format!("Profile {} has no credentials", profile.name))),
+    }
+}
+
+async fn run_credential_process(command: &str) -> Result<Credentials, Error> {
+    debug!("Running credential_process");
+    let output = if cfg!(windows) {
+        tokio::process::Command::new("cmd").arg("/C").arg(command).output().await?
+    } else {
+        tokio::process::Command::new("sh").arg("-c").arg(command).output().await?
+    };
+    if !output.status.success() {
+        return Err(anyhow!(format!(
+            "credential_process exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    parse_process_credentials(&output.stdout)
+}
+
+fn parse_process_credentials(stdout: &[u8]) -> Result<Credentials, Error> {
+    let credentials: ProcessCredentials = serde_json::from_slice(stdout)
+        .with_context(|| "Invalid json from credential_process")?;
+    if credentials.version != 1 {
+        return Err(anyhow!(format!("Unsupported credential_process version {}", credentials.version)));
+    }
+    Ok(Credentials {
+        aws_access_key: credentials.access_key_id,
+        aws_secret_key: credentials.secret_access_key.into(),
+        aws_sts_token: credentials.session_token.map(Secret::from),
+        expiration: credentials.expiration.and_then(|expiration| parse_expiration(&expiration)),
+    })
+}
+
+fn build_profile_assume_role_request(profile: &Profile, session: &SessionOptions) -> Result<AssumeRoleRequest, Error> {
+    let role_arn = profile.role_arn
+        .clone()
+        .ok_or_else(|| anyhow!(format!("Profile {} has no role_arn", profile.name)))?;
+    let token_code = match profile.mfa_serial {
+        Some(_) => session.token_code.clone(),
+        None => None,
+    };
+    // Values the request sets win over the profile's
+    let role_session_name = session.role_session_name
+        .clone()
+        .or_else(|| profile.role_session_name.clone())
+        .unwrap_or_else(default_session_name);
+    validate_session_name(&role_session_name)?;
+    let duration_seconds = session.duration_seconds.or(profile.duration_seconds);
+    if let Some(duration_seconds) = duration_seconds {
+        validate_duration_seconds(duration_seconds)?;
+    }
+    Ok(AssumeRoleRequest {
+        role_arn,
+        role_session_name,
+        duration_seconds,
+        external_id: profile.external_id.clone(),
+        serial_number: profile.mfa_serial.clone(),
+        token_code,
+        ..Default::default()
+    })
+}
+
+fn merge_sections(config_sections: Sections, credentials_sections: Sections) -> Profiles {
+    let mut merged: Sections = config_sections;
+    for (name, values) in credentials_sections {
+        merged.entry(name).or_default().extend(values);
+    }
+    let sso_sessions: Sections = merged
+        .iter()
+        .filter(|(name, _)| name.starts_with(SSO_SESSION_PREFIX))
+        .map(|(name, values)| (name.trim_start_matches(SSO_SESSION_PREFIX).trim().to_owned(), values.clone()))
+        .collect();
+    merged
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with(SSO_SESSION_PREFIX))
+        .map(|(name, values)| {
+            let value = |key: &str| values.get(key).cloned().flatten();
+            // Newer sso profiles point at a shared [sso-session x] section for the start url and region
+            let sso_session = value("sso_session");
+            let sso_value = |key: &str| value(key).or_else(|| {
+                sso_session.as_ref()
+                    .and_then(|sso_session| sso_sessions.get(sso_session))
+                    .and_then(|values| values.get(key).cloned().flatten())
+            });
+            let profile = Profile {
+                sso_start_url: sso_value("sso_start_url"),
+                sso_region: sso_value("sso_region"),
+                sso_account_id: value("sso_account_id"),
+                sso_role_name: value("sso_role_name"),
+                sso_session,
+                aws_access_key_id: value("aws_access_key_id"),
+                aws_secret_access_key: value("aws_secret_access_key").map(Secret::from),
+                aws_session_token: value("aws_session_token").map(Secret::from),
+                region: value("region"),
+                role_arn: value("role_arn"),
+                source_profile: value("source_profile"),
+                role_session_name: value("role_session_name"),
+                external_id: value("external_id"),
+                mfa_serial: value("mfa_serial"),
+                duration_seconds: value("duration_seconds").and_then(|duration| duration.parse().ok()),
+                credential_process: value("credential_process"),
+                name: name.clone(),
+            };
+            (name, profile)
+        })
+        .collect()
+}
+
+/// Parses the sections of an aws config or credentials file, config files name their sections `[profile x]`
+pub fn parse_profile_file(file_path: &PathBuf, is_config: bool) -> Result<Sections, Error> {
+    let profile_file = read_profile_file(file_path, is_config)?;
+    for diagnostic in profile_file.diagnostics {
+        warn!("{}:{} {}", diagnostic.file, diagnostic.line, diagnostic.message);
+    }
+    Ok(profile_file.sections)
+}
+
+fn read_profile_file(file_path: &PathBuf, is_config: bool) -> Result<ProfileFile, Error> {
+    let contents = fs::read_to_string(file_path).with_context(|| format!("could not read {:?}", file_path))?;
+    let file = file_path.to_string_lossy().into_owned();
+    let ini = parse_ini(&contents, &file);
+
+    let mut sections: Sections = BTreeMap::new();
+    let mut lines: BTreeMap<String, usize> = BTreeMap::new();
+    for section in ini.sections {
+        let name = profile_name(&section.name, is_config);
+        lines.entry(name.clone()).or_insert(section.line);
+        let values = sections.entry(name).or_default();
+        for entry in section.entries {
+            values.insert(entry.key, Some(entry.value));
+        }
+    }
+    let diagnostics = ini.diagnostics
+        .into_iter()
+        .map(|diagnostic| Diagnostic {
+            section: diagnostic.section.map(|section| profile_name(&section, is_config)),
+            ..diagnostic
+        })
+        .collect();
+    Ok(ProfileFile { file, sections, lines, diagnostics })
+}
+
+fn profile_name(section: &str, is_config: bool) -> String {
+    if is_config && section.starts_with("profile ") {
+        section.trim_start_matches("profile ").trim().to_owned()
+    } else {
+        section.to_owned()
+    }
+}
+
+pub fn build_credentials_path() -> Result<PathBuf, Error> {
+    if let Ok(file_path) = std::env::var("AWS_SHARED_CREDENTIALS_FILE") {
+        return Ok(PathBuf::from(file_path));
+    }
+    let home_dir = dirs::home_dir().ok_or_else(|| anyhow!("Missing home directory"))?;
+    let mut file_path = Path::new(home_dir.as_path()).join(".aws/credentials");
+    if !file_path.exists() {
+        let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("Missing config directory"))?;
+        file_path = Path::new(config_dir.as_path()).join(".aws/credentials");
+        if !file_path.exists() {
+            return Err(anyhow!("No aws credentials configuration found"));
+        }
+    }
+    Ok(file_path)
+}
+
+pub fn build_aws_config_path() -> Option<PathBuf> {
+    let file_path = match std::env::var("AWS_CONFIG_FILE") {
+        Ok(file_path) => PathBuf::from(file_path),
+        Err(_) => Path::new(dirs::home_dir()?.as_path()).join(".aws/config"),
+    };
+    if file_path.exists() {
+        Some(file_path)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::write_temp_file;
+
+    use super::*;
+
+    fn build_profiles() -> Profiles {
+        let config_path = write_temp_file("profile-config", "[default]\nregion = eu-west-1\n\n\
+            [profile identity]\nrole_arn = arn:aws:iam::111111111111:role/identity\nsource_profile = default\nmfa_serial = arn:aws:iam::000000000000:mfa/user\n\n\
+            [profile workload]\nrole_arn = arn:aws:iam::222222222222:role/workload\nsource_profile = identity\nregion = ap-southeast-2\nduration_seconds = 3600\n\n\
+            [profile process]\ncredential_process = echo\n\n\
+            [profile sso]\nsso_session = tasky\nsso_account_id = 333333333333\nsso_role_name = ReadOnly\n\n\
+            [sso-session tasky]\nsso_start_url = https://tasky.awsapps.com/start\nsso_region = eu-west-1\n\n\
+            [profile loop]\nrole_arn = arn:aws:iam::222222222222:role/loop\nsource_profile = loop-back\n\n\
+            [profile loop-back]\nrole_arn = arn:aws:iam::222222222222:role/loop-back\nsource_profile = loop\n");
+        let credentials_path = write_temp_file("profile-credentials", "[default]\naws_access_key_id = access\naws_secret_access_key = secret\n");
+        let profiles = merge_sections(
+            parse_profile_file(&config_path, true).unwrap(),
+            parse_profile_file(&credentials_path, false).unwrap(),
+        );
+        std::fs::remove_file(config_path).unwrap();
+        std::fs::remove_file(credentials_path).unwrap();
+        profiles
+    }
+
+    #[test]
+    fn test_load_profiles() {
+        let profiles = build_profiles();
+        let default = profiles.get("default").unwrap();
+        assert_eq!(default.aws_access_key_id, Some("access".to_owned()));
+        assert_eq!(default.region, Some("eu-west-1".to_owned()));
+        let workload = profiles.get("workload").unwrap();
+        assert_eq!(workload.source_profile, Some("identity".to_owned()));
+        assert_eq!(workload.duration_seconds, Some(3600));
+        assert_eq!(profiles.get("process").unwrap().credential_process, Some("echo".to_owned()));
+        let sso = profiles.get("sso").unwrap();
+        assert_eq!(sso.sso_start_url, Some("https://tasky.awsapps.com/start".to_owned()));
+        assert_eq!(sso.sso_region, Some("eu-west-1".to_owned()));
+        assert!(profiles.keys().all(|name| !name.starts_with("sso-session")));
+    }
+
+    #[test]
+    fn test_parse_profile_file_values() {
+        let file_path = write_temp_file("profile-values", "[default]\n\
+            # a comment\n\
+            credential_process = /usr/bin/creds --profile tasky ; inline comment\n\
+            role_session_name = \"tasky user\"\n");
+        let sections = parse_profile_file(&file_path, false).unwrap();
+        std::fs::remove_file(file_path).unwrap();
+        let default = sections.get("default").unwrap();
+        assert_eq!(default.get("credential_process").cloned().flatten(), Some("/usr/bin/creds --profile tasky".to_owned()));
+        assert_eq!(default.get("role_session_name").cloned().flatten(), Some("tasky user".to_owned()));
+    }
+
+    #[test]
+    fn test_summarise_profiles() {
+        let config_path = write_temp_file("summary-config", "[profile workload]\n\
+            role_arn = arn:aws:iam::222222222222:role/workload\n\
+            source_profile = missing\n\
+            \n\
+            [profile sso]\n\
+            sso_start_url = https://tasky.awsapps.com/start\n\
+            not a key value\n");
+        let credentials_path = write_temp_file("summary-credentials", "[default]\n\
+            aws_access_key_id = AKIAEXAMPLE\n\
+            aws_secret_access_key = wJalrEXAMPLEKEY\n\
+            aws_session_token = FwoGEXAMPLETOKEN\n\
+            \n\
+            [half]\n\
+            aws_access_key_id = AKIAHALF\n");
+        let response = summarise_profiles(
+            Some(read_profile_file(&config_path, true).unwrap()),
+            Some(read_profile_file(&credentials_path, false).unwrap()),
+        );
+        std::fs::remove_file(config_path).unwrap();
+        std::fs::remove_file(credentials_path).unwrap();
+
+        let profile = |name: &str| response.profiles.iter().find(|profile| profile.name == name).unwrap();
+        assert_eq!(profile("default").kind, ProfileKind::Static);
+        assert!(profile("default").has_session_token);
+        assert!(profile("default").problems.is_empty());
+        assert_eq!(profile("half").problems.len(), 1);
+        assert_eq!(profile("half").problems[0].line, 6);
+        let workload = profile("workload");
+        assert_eq!(workload.kind, ProfileKind::AssumeRole);
+        assert_eq!(workload.problems[0].message, "No aws profile named missing");
+        assert_eq!(workload.problems[0].line, 1);
+        let sso = profile("sso");
+        assert_eq!(sso.kind, ProfileKind::Sso);
+        assert_eq!(sso.problems[0].line, 7);
+        assert_eq!(sso.problems.len(), 4);
+        assert!(response.problems.is_empty());
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(!json.contains("AKIA") && !json.contains("wJalr") && !json.contains("FwoG"));
+    }
+
+    #[test]
+    fn test_build_profile_chain() {
+        let profiles = build_profiles();
+        let (source, roles) = build_profile_chain("workload", &profiles).unwrap();
+        assert_eq!(source.name, "default");
+        let names: Vec<&str> = roles.iter().map(|role| role.name.as_str()).collect();
+        assert_eq!(names, vec!["identity", "workload"]);
+    }
+
+    #[test]
+    fn test_build_profile_chain_fail() {
+        let profiles = build_profiles();
+        assert!(build_profile_chain("loop", &profiles).is_err());
+        assert!(build_profile_chain("missing", &profiles).is_err());
+    }
+
+    #[test]
+    fn test_requested_region() {
+        let config = Config {
+            profiles: build_profiles(),
+            ..Default::default()
+        };
+        let workload = Some("workload".to_owned());
+        assert_eq!(requested_region(&None, &workload, &config), Some("ap-southeast-2".to_owned()));
+        assert_eq!(requested_region(&Some("us-east-1".to_owned()), &workload, &config), Some("us-east-1".to_owned()));
+        assert_eq!(requested_region(&None, &Some("identity".to_owned()), &config), None);
+        assert_eq!(requested_region(&None, &Some("missing".to_owned()), &config), None);
+        assert_eq!(requested_region(&None, &None, &config), None);
+    }
+
+    #[test]
+    fn test_build_profile_assume_role_request() {
+        let profiles = build_profiles();
+        let session = SessionOptions {
+            token_code: Some("123456".to_owned()),
+            ..Default::default()
+        };
+        let request = build_profile_assume_role_request(profiles.get("identity").unwrap(), &session).unwrap();
+        assert_eq!(request.serial_number, Some("arn:aws:iam::000000000000:mfa/user".to_owned()));
+        assert_eq!(request.token_code, Some("123456".to_owned()));
+        let request = build_profile_assume_role_request(profiles.get("workload").unwrap(), &session).unwrap();
+        assert_eq!(request.token_code, None);
+        assert_eq!(request.duration_seconds, Some(3600));
+    }
+
+    #[test]
+    fn test_build_profile_assume_role_request_precedence() {
+        let profile = Profile {
+            name: "workload".to_owned(),
+            role_arn: Some("arn:aws:iam::222222222222:role/workload".to_owned()),
+            role_session_name: Some("profile-session".to_owned()),
+            duration_seconds: Some(3600),
+            ..Default::default()
+        };
+        let request = build_profile_assume_role_request(&profile, &SessionOptions::default()).unwrap();
+        assert_eq!(request.role_session_name, "profile-session");
+        assert_eq!(request.duration_seconds, Some(3600));
+
+        let session = SessionOptions {
+            role_session_name: Some("request-session".to_owned()),
+            duration_seconds: Some(900),
+            ..Default::default()
+        };
+        let request = build_profile_assume_role_request(&profile, &session).unwrap();
+        assert_eq!(request.role_session_name, "request-session");
+        assert_eq!(request.duration_seconds, Some(900));
+    }
+
+    #[test]
+    fn test_build_profile_assume_role_request_fail() {
+        let profile = Profile {
+            name: "workload".to_owned(),
+            role_arn: Some("arn:aws:iam::222222222222:role/workload".to_owned()),
+            duration_seconds: Some(60),
+            ..Default::default()
+        };
+        assert!(build_profile_assume_role_request(&profile, &SessionOptions::default()).is_err());
+        let profile = Profile {
+            role_session_name: Some("has spaces".to_owned()),
+            duration_seconds: None,
+            ..profile
+        };
+        assert!(build_profile_assume_role_request(&profile, &SessionOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_process_credentials() {
+        let credentials = parse_process_credentials(br#"{"Version": 1, "AccessKeyId": "access", "SecretAccessKey": "secret", "SessionToken": "token", "Expiration": "2020-09-01T10:00:00Z"}"#).unwrap();
+        assert_eq!(credentials.aws_access_key, "access");
+        assert_eq!(credentials.aws_sts_token, Some("token".into()));
+        assert!(credentials.expiration.is_some());
+    }
+
+    #[test]
+    fn test_parse_process_credentials_fail() {
+        assert!(parse_process_credentials(br#"{"Version": 2, "AccessKeyId": "access", "SecretAccessKey": "secret"}"#).is_err());
+        assert!(parse_process_credentials(b"not json").is_err());
+    }
+}
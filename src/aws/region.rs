@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use rusoto_core::Region;
+
+use crate::aws::manager::Config;
+
+/// Regions enabled by default in every commercial account, opt-in regions need to be listed in `enabled_regions`
+const DEFAULT_ENABLED_REGIONS: [Region; 16] = [
+    Region::UsEast1,
+    Region::UsEast2,
+    Region::UsWest1,
+    Region::UsWest2,
+    Region::CaCentral1,
+    Region::SaEast1,
+    Region::EuWest1,
+    Region::EuWest2,
+    Region::EuWest3,
+    Region::EuCentral1,
+    Region::EuNorth1,
+    Region::ApSouth1,
+    Region::ApNortheast1,
+    Region::ApNortheast2,
+    Region::ApSoutheast1,
+    Region::ApSoutheast2,
+];
+
+/// Resolves the region a request should run against, preferring the region on the request itself,
+/// then the region in the manager config and finally the region of the aws profile in use.
+pub fn resolve_region(requested: &Option<String>, config: &Config) -> Result<Region, Error> {
+    let region = requested
+        .as_ref()
+        .or(config.region.as_ref())
+        .or(config.profile_region.as_ref());
+
+    match region {
+        Some(region) => parse_region(region),
+        None => Ok(Region::EuWest1),
+    }
+}
+
+/// Resolves every region a request fans out to, either all enabled regions, the listed regions or the single
+/// region from `resolve_region`.
+pub fn resolve_regions(
+    region: &Option<String>,
+    regions: &Option<Vec<String>>,
+    all_regions: bool,
+    config: &Config,
+) -> Result<Vec<Region>, Error> {
+    let regions = if all_regions {
+        match &config.enabled_regions {
+            Some(enabled_regions) => parse_regions(enabled_regions)?,
+            None => DEFAULT_ENABLED_REGIONS.to_vec(),
+        }
+    } else {
+        match regions {
+            Some(regions) if !regions.is_empty() => parse_regions(regions)?,
+            _ => vec![resolve_region(region, config)?],
+        }
+    };
+    Ok(regions)
+}
+
+fn parse_regions(regions: &[String]) -> Result<Vec<Region>, Error> {
+    let mut parsed: Vec<Region> = Vec::new();
+    for region in regions {
+        let region = parse_region(region)?;
+        if !parsed.contains(&region) {
+            parsed.push(region);
+        }
+    }
+    Ok(parsed)
+}
+
+pub fn parse_region(region: &str) -> Result<Region, Error> {
+    Region::from_str(region.trim()).map_err(|err| anyhow!(format!("Invalid region `{}`: {}", region, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_region_prefers_request() {
+        let config = Config {
+            region: Some("us-east-1".to_owned()),
+            profile_region: Some("ap-southeast-2".to_owned()),
+            ..Default::default()
+        };
+        let region = resolve_region(&Some("eu-west-1".to_owned()), &config).unwrap();
+        assert_eq!(region, Region::EuWest1);
+    }
+
+    #[test]
+    fn test_resolve_region_falls_back_to_config() {
+        let config = Config {
+            region: Some("us-east-1".to_owned()),
+            profile_region: Some("ap-southeast-2".to_owned()),
+            ..Default::default()
+        };
+        let region = resolve_region(&None, &config).unwrap();
+        assert_eq!(region, Region::UsEast1);
+    }
+
+    #[test]
+    fn test_resolve_region_falls_back_to_profile() {
+        let config = Config {
+            profile_region: Some("ap-southeast-2".to_owned()),
+            ..Default::default()
+        };
+        let region = resolve_region(&None, &config).unwrap();
+        assert_eq!(region, Region::ApSoutheast2);
+    }
+
+    #[test]
+    fn test_resolve_regions_listed() {
+        let regions = Some(vec!["us-east-1".to_owned(), "ap-southeast-2".to_owned(), "us-east-1".to_owned()]);
+        let regions = resolve_regions(&None, &regions, false, &Config::default()).unwrap();
+        assert_eq!(regions, vec![Region::UsEast1, Region::ApSoutheast2]);
+    }
+
+    #[test]
+    fn test_resolve_regions_all_enabled() {
+        let config = Config {
+            enabled_regions: Some(vec!["eu-west-1".to_owned(), "af-south-1".to_owned()]),
+            ..Default::default()
+        };
+        let regions = resolve_regions(&None, &None, true, &config).unwrap();
+        assert_eq!(regions, vec![Region::EuWest1, Region::AfSouth1]);
+        let regions = resolve_regions(&None, &None, true, &Config::default()).unwrap();
+        assert_eq!(regions.len(), DEFAULT_ENABLED_REGIONS.len());
+    }
+
+    #[test]
+    fn test_resolve_regions_single() {
+        let region = Some("us-east-1".to_owned());
+        let regions = resolve_regions(&region, &Some(vec![]), false, &Config::default()).unwrap();
+        assert_eq!(regions, vec![Region::UsEast1]);
+    }
+
+    #[test]
+    fn test_resolve_region_fail() {
+        let config = Config::default();
+        assert!(resolve_region(&Some("moon-east-1".to_owned()), &config).is_err());
+    }
+}
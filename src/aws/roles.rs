@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::credentials::{account_id_from_arn, validate_role_arn};
+use crate::aws::manager::Config;
+use crate::aws::region::parse_region;
+use crate::error::{ErrorWrapper, ValidationError};
+use crate::extract_rejection;
+use crate::state::AppState;
+
+/// Environment label shown next to a role so prod is hard to mistake for dev
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    Dev,
+    Staging,
+    Prod,
+}
+
+/// A role registered under an alias, requests can name it with `role` in place of its arn
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct NamedRole {
+    /// Taken from the path when updating a role
+    #[serde(default)]
+    pub alias: String,
+    pub role_arn: String,
+    /// Account id of the role, taken from the role arn when not given
+    #[serde(default)]
+    pub account: String,
+    pub environment: Option<Environment>,
+    /// Region used for requests naming the role that don't ask for a region
+    pub default_region: Option<String>,
+    /// Hex colour the ui shows the role in, eg `#d93f0b`
+    pub colour: Option<String>,
+}
+
+pub async fn list_roles_filter(state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config())?;
+    Ok(warp::reply::json(&config.named_roles))
+}
+
+pub async fn get_role_filter(alias: String, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config())?;
+    match find_role(&config, &alias) {
+        Some(role) => Ok(warp::reply::json(role)),
+        None => Err(reject::not_found()),
+    }
+}
+
+pub async fn create_role_filter(role: NamedRole, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let role = extract_rejection!(validate_role(role))?;
+    extract_rejection!(state.update(|config| {
+        if find_role(config, &role.alias).is_some() {
+            return Err(anyhow!(ValidationError { message: format!("A role named {} already exists", role.alias) }));
+        }
+        config.named_roles.push(role.clone());
+        Ok(())
+    }))?;
+    Ok(warp::reply::with_status(warp::reply::json(&role), warp::http::StatusCode::CREATED))
+}
+
+/// Replaces the role, renaming it when the body has a different alias
+pub async fn update_role_filter(alias: String, role: NamedRole, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let role = extract_rejection!(validate_role(NamedRole {
+        alias: if role.alias.is_empty() { alias.clone() } else { role.alias },
+        ..role
+    }))?;
+    let (_, is_found) = extract_rejection!(state.update(|config| {
+        let index = match config.named_roles.iter().position(|named_role| named_role.alias == alias) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        if role.alias != alias && find_role(config, &role.alias).is_some() {
+            return Err(anyhow!(ValidationError { message: format!("A role named {} already exists", role.alias) }));
+        }
+        config.named_roles[index] = role.clone();
+        Ok(true)
+    }))?;
+    if !is_found {
+        return Err(reject::not_found());
+    }
+    Ok(warp::reply::json(&role))
+}
+
+pub async fn delete_role_filter(alias: String, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let (_, is_found) = extract_rejection!(state.update(|config| {
+        let index = match config.named_roles.iter().position(|named_role| named_role.alias == alias) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        config.named_roles.remove(index);
+        Ok(true)
+    }))?;
+    if !is_found {
+        return Err(reject::not_found());
+    }
+    Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT))
+}
+
+pub fn find_role<'a>(config: &'a Config, alias: &str) -> Option<&'a NamedRole> {
+    config.named_roles.iter().find(|named_role| named_role.alias == alias)
+}
+
+/// The role arn of a request along with the default region of its named role, `role` is looked up in the
+/// registry and may be given instead of `role_arn`
+pub fn resolve_role_arn(role_arn: &str, role: &Option<String>, config: &Config) -> Result<(String, Option<String>), Error> {
+    let alias = match role {
+        Some(alias) => alias,
+        None => return Ok((role_arn.to_owned(), None)),
+    };
+    let named_role = find_role(config, alias)
+        .ok_or_else(|| anyhow!(ValidationError { message: format!("No role named {}", alias) }))?;
+    if !role_arn.is_empty() && role_arn != named_role.role_arn {
+        return Err(anyhow!(ValidationError {
+            message: format!("Role {} is {}, not the role_arn {}", alias, named_role.role_arn, role_arn)
+        }));
+    }
+    Ok((named_role.role_arn.clone(), named_role.default_region.clone()))
+}
+
+/// The role arns of a request naming several roles along with the default region of each named role, the
+/// aliases in `roles` come after the `role_arns`
+pub fn resolve_role_arns(role_arns: &[String], roles: &[String], config: &Config) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut resolved: Vec<(String, Option<String>)> = role_arns.iter().map(|role_arn| (role_arn.clone(), None)).collect();
+    for alias in roles {
+        let (role_arn, default_region) = resolve_role_arn("", &Some(alias.clone()), config)?;
+        if !resolved.iter().any(|(resolved_arn, _)| resolved_arn == &role_arn) {
+            resolved.push((role_arn, default_region));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Checks the role and fills in its account from the role arn
+fn validate_role(role: NamedRole) -> Result<NamedRole, Error> {
+    let invalid = |err: Error| anyhow!(ValidationError { message: format!("{}", err) });
+    let is_alias_char = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if role.alias.is_empty() || !role.alias.chars().all(is_alias_char) {
+        return Err(invalid(anyhow!(format!(
+            "`{}` is not a valid alias, use letters, digits, `-`, `_` and `.`",
+            role.alias
+        ))));
+    }
+    validate_role_arn(&role.role_arn).map_err(invalid)?;
+    let account = account_id_from_arn(&role.role_arn).unwrap_or_default();
+    if !role.account.is_empty() && role.account != account {
+        return Err(invalid(anyhow!(format!("Account {} doesn't match the role arn {}", role.account, role.role_arn))));
+    }
+    if let Some(region) = &role.default_region {
+        parse_region(region).map_err(invalid)?;
+    }
+    if let Some(colour) = &role.colour {
+        let is_hex = colour.starts_with('#')
+            && (colour.len() == 4 || colour.len() == 7)
+            && colour[1..].chars().all(|c| c.is_ascii_hexdigit());
+        if !is_hex {
+            return Err(invalid(anyhow!(format!("`{}` is not a hex colour like #d93f0b", colour))));
+        }
+    }
+    Ok(NamedRole { account, ..role })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_role() -> NamedRole {
+        NamedRole {
+            alias: "billing-prod".to_owned(),
+            role_arn: "arn:aws:iam::123456789012:role/tasky-read-only".to_owned(),
+            environment: Some(Environment::Prod),
+            default_region: Some("eu-west-2".to_owned()),
+            colour: Some("#d93f0b".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_role() {
+        let role = validate_role(build_role()).unwrap();
+        assert_eq!(role.account, "123456789012");
+
+        let invalid = vec![
+            NamedRole { alias: "billing prod".to_owned(), ..build_role() },
+            NamedRole { role_arn: "arn:aws:iam::123456789012:user/tasky".to_owned(), ..build_role() },
+            NamedRole { account: "210987654321".to_owned(), ..build_role() },
+            NamedRole { default_region: Some("mars-north-1".to_owned()), ..build_role() },
+            NamedRole { colour: Some("red".to_owned()), ..build_role() },
+        ];
+        for role in invalid {
+            let err = validate_role(role).unwrap_err();
+            assert!(err.downcast_ref::<ValidationError>().is_some());
+        }
+    }
+
+    #[test]
+    fn test_resolve_role_arn() {
+        let config = Config {
+            named_roles: vec![build_role()],
+            ..Default::default()
+        };
+        let role_arn = "arn:aws:iam::123456789012:role/tasky-read-only";
+        assert_eq!(
+            resolve_role_arn("", &Some("billing-prod".to_owned()), &config).unwrap(),
+            (role_arn.to_owned(), Some("eu-west-2".to_owned()))
+        );
+        assert_eq!(resolve_role_arn(role_arn, &None, &config).unwrap(), (role_arn.to_owned(), None));
+        assert!(resolve_role_arn("", &Some("billing-dev".to_owned()), &config).is_err());
+        assert!(resolve_role_arn("arn:aws:iam::123456789012:role/other", &Some("billing-prod".to_owned()), &config).is_err());
+    }
+
+    #[test]
+    fn test_resolve_role_arns() {
+        let config = Config {
+            named_roles: vec![build_role()],
+            ..Default::default()
+        };
+        let role_arns = vec!["arn:aws:iam::210987654321:role/tasky".to_owned()];
+        let resolved = resolve_role_arns(&role_arns, &["billing-prod".to_owned()], &config).unwrap();
+        assert_eq!(resolved, vec![
+            ("arn:aws:iam::210987654321:role/tasky".to_owned(), None),
+            ("arn:aws:iam::123456789012:role/tasky-read-only".to_owned(), Some("eu-west-2".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn test_named_role_serialises() {
+        let role: NamedRole = serde_json::from_str(r##"{
+            "alias": "billing-prod",
+            "role_arn": "arn:aws:iam::123456789012:role/tasky-read-only",
+            "environment": "staging",
+            "default_region": null,
+            "colour": "#0e8a16"
+        }"##).unwrap();
+        assert_eq!(role.environment, Some(Environment::Staging));
+        assert_eq!(role.account, "");
+    }
+}
@@ -2,7 +2,6 @@ use std::sync::Arc;
 
 use futures::future::join_all;
 use rusoto_core::Region;
-use rusoto_credential::StaticProvider;
 use rusoto_s3::{GetObjectOutput, GetObjectRequest, S3, S3Client};
 use serde_json::Value;
 use tokio::io::AsyncReadExt;
@@ -10,18 +9,13 @@ use tokio::io::AsyncReadExt;
 use crate::aws::client::HttpClient;
 use crate::aws::credentials::Credentials;
 
-pub fn build_s3_client(client: Arc<HttpClient>, creds: Credentials) -> S3Client {
-    let cred_provider = StaticProvider::new(
-        creds.aws_access_key,
-        creds.aws_secret_key,
-        Some(creds.aws_sts_token),
-        None,
-    );
-    S3Client::new_with(client, cred_provider, Region::EuWest1)
+pub fn build_s3_client(client: Arc<HttpClient>, creds: Credentials, region: Region) -> S3Client {
+    let cred_provider = creds.build_provider();
+    S3Client::new_with(client, cred_provider, region)
 }
 
-pub async fn get_object(client: S3Client, bucket_file: &str) -> GetObjectOutput {
-    let (bucket, key) = build_bucket_path(&bucket_file);
+pub async fn _get_object(client: S3Client, bucket_file: &str) -> GetObjectOutput {
+    let (bucket, key) = _build_bucket_path(bucket_file);
     let request = GetObjectRequest {
         bucket,
         if_match: None,
@@ -46,7 +40,7 @@ pub async fn get_object(client: S3Client, bucket_file: &str) -> GetObjectOutput
     client.clone().get_object(request).await.unwrap()
 }
 
-fn build_bucket_path(bucket_file: &str) -> (String, String) {
+fn _build_bucket_path(bucket_file: &str) -> (String, String) {
     let arguments: Vec<&str> = bucket_file.split(':').collect();
     if arguments.is_empty() {
         panic!("Bucket file was not in the format of 'BUCKET_NAME:BUCKET_KEY'")
@@ -57,14 +51,14 @@ fn build_bucket_path(bucket_file: &str) -> (String, String) {
 }
 
 
-pub fn build_s3_clients(creds: Vec<Credentials>, client: &Arc<HttpClient>) -> Vec<S3Client> {
+pub fn _build_s3_clients(creds: Vec<Credentials>, client: &Arc<HttpClient>, region: &Region) -> Vec<S3Client> {
     creds
         .into_iter()
-        .map(|creds| build_s3_client(client.clone(), creds))
+        .map(|creds| build_s3_client(client.clone(), creds, region.clone()))
         .collect()
 }
 
-fn deserialise_objects(objects: Vec<String>) -> Vec<Value> {
+fn _deserialise_objects(objects: Vec<String>) -> Vec<Value> {
     let objects = objects
         .into_iter()
         .map(|object| serde_json::from_str(&object).expect("Unable to read object"))
@@ -92,10 +86,10 @@ async fn _get_objects_results(objects: Vec<GetObjectOutput>) -> Vec<String> {
     all_objects_results
 }
 
-async fn _get_objects(clients: Vec<S3Client>, bucket_file: &String) -> Vec<GetObjectOutput> {
+async fn _get_objects(clients: Vec<S3Client>, bucket_file: &str) -> Vec<GetObjectOutput> {
     let get_object_futures = clients
         .into_iter()
-        .map(|client| get_object(client, &bucket_file));
+        .map(|client| _get_object(client, bucket_file));
     join_all(get_object_futures).await
 }
 
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use chrono::{FixedOffset, Utc};
+use rusoto_core::Region;
+use rusoto_credential::StaticProvider;
+use rusoto_sts::{GetSessionTokenRequest, Sts, StsClient};
+use warp::reject;
+use warp::Rejection;
+
+use crate::aws::client;
+use crate::aws::client::HttpClient;
+use crate::aws::credentials::{match_rusoto_errors, parse_expiration, Credentials};
+use crate::aws::credentials_file::write_profile_credentials;
+use crate::aws::dto::{SessionRefreshRequest, SessionStatus};
+use crate::aws::manager::Config;
+use crate::aws::region::resolve_region;
+use crate::error::{CredentialError, ErrorWrapper};
+use crate::extract_rejection;
+use crate::state::AppState;
+
+const MIN_SESSION_TOKEN_DURATION: i64 = 900;
+const MAX_SESSION_TOKEN_DURATION: i64 = 129_600;
+
+pub async fn get_session_filter(state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config())?;
+    Ok(warp::reply::json(&build_session_status(&config)))
+}
+
+/// Swaps the long lived keys for a new session token and stores it as the temp credentials
+pub async fn refresh_session_filter(request: SessionRefreshRequest, state: AppState) -> Result<impl warp::Reply, Rejection> {
+    let config = extract_rejection!(state.config())?;
+    let client = Arc::new(extract_rejection!(client::new_client())?);
+    let region = extract_rejection!(resolve_region(&None, &config))?;
+
+    let target_profile = extract_rejection!(select_target_profile(&config, &request))?;
+    let credentials = extract_rejection!(get_session_token(&config, client, &request, &region).await)?;
+    if let Some(profile) = &target_profile {
+        let file_path = extract_rejection!(write_profile_credentials(profile, &credentials))?;
+        debug!("Wrote the refreshed session to profile {} in {:?}", profile, file_path);
+    }
+    let (config, _) = extract_rejection!(state.update(|config| {
+        apply_session_credentials(config, credentials);
+        // Loading the config reads the session back from the profile it was written to, without one the
+        // session is kept in the manager file until it expires
+        config.aws_sts_profile = target_profile;
+        Ok(())
+    }))?;
+
+    Ok(warp::reply::json(&build_session_status(&config)))
+}
+
+fn build_session_status(config: &Config) -> SessionStatus {
+    SessionStatus {
+        valid: config.is_token_valid(),
+        sts_profile: config.aws_sts_profile.clone(),
+        expiration: config.aws_session_expiration,
+        expires_in_seconds: config.aws_session_expiration
+            .map(|expiration| expiration.timestamp() - Utc::now().timestamp()),
+        mfa_serial: config.mfa_serial.clone(),
+    }
+}
+
+async fn get_session_token(config: &Config, client: Arc<HttpClient>, request: &SessionRefreshRequest, region: &Region) -> Result<Credentials, Error> {
+    let session_token_request = build_session_token_request(config, request)?;
+    let sent_mfa = session_token_request.serial_number.is_some() && session_token_request.token_code.is_some();
+    if config.aws_access_key_id.is_empty() || config.aws_secret_access_key.is_empty() {
+        return Err(anyhow!("aws_access_key_id and aws_secret_access_key are needed to refresh the session"));
+    }
+    // GetSessionToken has to be called with the long lived keys, never with an existing session
+    let cred_provider = StaticProvider::new(
+        config.aws_access_key_id.clone(),
+        config.aws_secret_access_key.expose().to_owned(),
+        None,
+        None,
+    );
+    let sts_client = StsClient::new_with(client, cred_provider, region.clone());
+
+    debug!("Getting a session token");
+    let response = sts_client
+        .get_session_token(session_token_request)
+        .await
+        .map_err(|err| match_session_mfa_errors(match_rusoto_errors(err), sent_mfa))?;
+    let credentials = response.credentials
+        .ok_or_else(|| anyhow!("Missing credentials from get session token"))?;
+
+    Ok(Credentials {
+        expiration: parse_expiration(&credentials.expiration),
+        aws_access_key: credentials.access_key_id,
+        aws_secret_key: credentials.secret_access_key.into(),
+        aws_sts_token: Some(credentials.session_token.into()),
+    })
+}
+
+fn build_session_token_request(config: &Config, request: &SessionRefreshRequest) -> Result<GetSessionTokenRequest, Error> {
+    if let Some(duration_seconds) = request.duration_seconds {
+        if !(MIN_SESSION_TOKEN_DURATION..=MAX_SESSION_TOKEN_DURATION).contains(&duration_seconds) {
+            return Err(anyhow!(format!(
+                "duration_seconds must be between {} and {}, was {}",
+                MIN_SESSION_TOKEN_DURATION, MAX_SESSION_TOKEN_DURATION, duration_seconds
+            )));
+        }
+    }
+
+    let token_code = request.token_code.clone().filter(|token_code| !token_code.trim().is_empty());
+    if let Some(serial_number) = &config.mfa_serial {
+        if token_code.is_none() {
+            return Err(anyhow!(CredentialError::SessionMfaRequired {
+                serial_number: serial_number.clone(),
+            }));
+        }
+    }
+
+    Ok(GetSessionTokenRequest {
+        duration_seconds: request.duration_seconds,
+        serial_number: config.mfa_serial.clone(),
+        token_code: config.mfa_serial.as_ref().and(token_code),
+    })
+}
+
+/// The profile the refreshed session is shared through, never the one holding the long lived keys
+fn select_target_profile(config: &Config, request: &SessionRefreshRequest) -> Result<Option<String>, Error> {
+    let profile = request.profile.clone().or_else(|| config.aws_sts_profile.clone());
+    if config.aws_use_default_credentials && profile.as_deref() == Some("default") {
+        return Err(anyhow!("The default profile holds the long lived keys, pick another profile for the session"));
+    }
+    Ok(profile)
+}
+
+fn apply_session_credentials(config: &mut Config, credentials: Credentials) {
+    config.aws_temp_access_key_id = Some(credentials.aws_access_key);
+    config.aws_temp_secret_access_key = Some(credentials.aws_secret_key);
+    config.aws_session_token = credentials.aws_sts_token;
+    config.aws_session_expiration = credentials.expiration
+        .map(|expiration| expiration.with_timezone(&FixedOffset::east(0)));
+}
+
+/// Only a refresh that sent a code can have it rejected, other access denied errors are left alone
+fn match_session_mfa_errors(err: Error, sent_mfa: bool) -> Error {
+    let message = format!("{}", err);
+    if sent_mfa && message.contains("MultiFactorAuthentication") {
+        anyhow!(CredentialError::SessionMfaRejected { message })
+    } else {
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn build_config(mfa_serial: Option<&str>) -> Config {
+        Config {
+            aws_access_key_id: "access".to_owned(),
+            aws_secret_access_key: "secret".into(),
+            mfa_serial: mfa_serial.map(|mfa_serial| mfa_serial.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_session_token_request() {
+        let config = build_config(Some("arn:aws:iam::123456789012:mfa/tasky"));
+        let request = build_session_token_request(&config, &SessionRefreshRequest {
+            token_code: Some("123456".to_owned()),
+            duration_seconds: Some(3600),
+            profile: None,
+        }).unwrap();
+        assert_eq!(request.serial_number, Some("arn:aws:iam::123456789012:mfa/tasky".to_owned()));
+        assert_eq!(request.token_code, Some("123456".to_owned()));
+        assert_eq!(request.duration_seconds, Some(3600));
+    }
+
+    #[test]
+    fn test_build_session_token_request_without_mfa() {
+        let request = build_session_token_request(&build_config(None), &SessionRefreshRequest {
+            token_code: Some("123456".to_owned()),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(request.serial_number, None);
+        assert_eq!(request.token_code, None);
+    }
+
+    #[test]
+    fn test_build_session_token_request_mfa_required() {
+        let config = build_config(Some("arn:aws:iam::123456789012:mfa/tasky"));
+        let err = build_session_token_request(&config, &SessionRefreshRequest {
+            token_code: Some(" ".to_owned()),
+            ..Default::default()
+        }).unwrap_err();
+        assert!(matches!(err.downcast_ref::<CredentialError>(), Some(CredentialError::SessionMfaRequired { .. })));
+    }
+
+    #[test]
+    fn test_build_session_token_request_duration() {
+        let request = SessionRefreshRequest {
+            duration_seconds: Some(600),
+            ..Default::default()
+        };
+        assert!(build_session_token_request(&build_config(None), &request).is_err());
+    }
+
+    #[test]
+    fn test_match_session_mfa_errors() {
+        let err = match_session_mfa_errors(anyhow!("MultiFactorAuthentication failed with invalid MFA one time pass code."), true);
+        assert!(err.downcast_ref::<CredentialError>().is_some());
+        let err = match_session_mfa_errors(
+            anyhow!("User: arn:aws:iam::123456789012:user/dev-MFA is not authorized to perform: sts:GetSessionToken"),
+            true,
+        );
+        assert!(err.downcast_ref::<CredentialError>().is_none());
+        let err = match_session_mfa_errors(anyhow!("MultiFactorAuthentication failed with invalid MFA one time pass code."), false);
+        assert!(err.downcast_ref::<CredentialError>().is_none());
+    }
+
+    #[test]
+    fn test_select_target_profile() {
+        let config = Config {
+            aws_use_default_credentials: true,
+            aws_sts_profile: Some("session".to_owned()),
+            ..build_config(None)
+        };
+        let mut request = SessionRefreshRequest::default();
+        assert_eq!(select_target_profile(&config, &request).unwrap(), Some("session".to_owned()));
+        request.profile = Some("default".to_owned());
+        assert!(select_target_profile(&config, &request).is_err());
+        assert_eq!(select_target_profile(&build_config(None), &SessionRefreshRequest::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_apply_session_credentials() {
+        let mut config = build_config(None);
+        apply_session_credentials(&mut config, Credentials {
+            aws_access_key: "temp-access".to_owned(),
+            aws_secret_key: "temp-secret".into(),
+            aws_sts_token: Some("token".into()),
+            expiration: Some(Utc::now() + Duration::hours(1)),
+        });
+        assert_eq!(config.aws_temp_access_key_id, Some("temp-access".to_owned()));
+
+        let status = build_session_status(&config);
+        assert!(status.valid);
+        assert!(status.expires_in_seconds.unwrap() > 3500);
+    }
+
+    #[test]
+    fn test_session_status_expired() {
+        let config = Config {
+            aws_session_token: Some("token".into()),
+            aws_session_expiration: Some((Utc::now() - Duration::minutes(1)).with_timezone(&FixedOffset::east(0))),
+            ..build_config(None)
+        };
+        let status = build_session_status(&config);
+        assert!(!status.valid);
+        assert!(status.expires_in_seconds.unwrap() < 0);
+    }
+}
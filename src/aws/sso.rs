@@ -0,0 +1,201 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::aws::credentials::Credentials;
+use crate::aws::profile::Profile;
+use crate::error::CredentialError;
+
+/// A token cached by `aws sso login` in ~/.aws/sso/cache
+#[derive(Debug, Deserialize)]
+struct CachedToken {
+    #[serde(rename = "startUrl")]
+    start_url: Option<String>,
+    #[serde(rename = "accessToken")]
+    access_token: String,
+    #[serde(rename = "expiresAt")]
+    expires_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoleCredentialsResponse {
+    #[serde(rename = "roleCredentials")]
+    role_credentials: RoleCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoleCredentials {
+    #[serde(rename = "accessKeyId")]
+    access_key_id: String,
+    #[serde(rename = "secretAccessKey")]
+    secret_access_key: String,
+    #[serde(rename = "sessionToken")]
+    session_token: String,
+    /// Milliseconds since the epoch
+    #[serde(rename = "expiration")]
+    expiration: i64,
+}
+
+pub fn is_sso_profile(profile: &Profile) -> bool {
+    profile.sso_start_url.is_some() || profile.sso_session.is_some()
+}
+
+/// Exchanges the cached sso access token for the profile's account and role credentials
+pub async fn sso_credentials(profile: &Profile) -> Result<Credentials, Error> {
+    let start_url = required(&profile.sso_start_url, "sso_start_url", profile)?;
+    let sso_region = required(&profile.sso_region, "sso_region", profile)?;
+    let account_id = required(&profile.sso_account_id, "sso_account_id", profile)?;
+    let role_name = required(&profile.sso_role_name, "sso_role_name", profile)?;
+
+    let access_token = find_cached_token(&build_sso_cache_path()?, start_url)?
+        .filter(|token| !is_expired(token))
+        .map(|token| token.access_token)
+        .ok_or_else(|| anyhow!(CredentialError::SsoSessionExpired { profile: profile.name.clone() }))?;
+
+    debug!("Getting sso role credentials for profile {}", profile.name);
+    let response = reqwest::Client::new()
+        .get(&format!("https://portal.sso.{}.amazonaws.com/federation/credentials", sso_region))
+        .query(&[("account_id", account_id), ("role_name", role_name)])
+        .header("x-amz-sso_bearer_token", access_token)
+        .send()
+        .await?;
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(anyhow!(CredentialError::SsoSessionExpired { profile: profile.name.clone() }));
+    }
+    if !status.is_success() {
+        return Err(anyhow!(format!(
+            "Sso GetRoleCredentials failed with {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    let body = response.text().await?;
+    let role_credentials = serde_json::from_str::<RoleCredentialsResponse>(&body)
+        .with_context(|| "Invalid json from sso GetRoleCredentials")?
+        .role_credentials;
+
+    Ok(Credentials {
+        aws_access_key: role_credentials.access_key_id,
+        aws_secret_key: role_credentials.secret_access_key.into(),
+        aws_sts_token: Some(role_credentials.session_token.into()),
+        expiration: Some(Utc.timestamp_millis(role_credentials.expiration)),
+    })
+}
+
+fn required<'a>(value: &'a Option<String>, key: &str, profile: &Profile) -> Result<&'a str, Error> {
+    value
+        .as_deref()
+        .ok_or_else(|| anyhow!(format!("Sso profile {} is missing {}", profile.name, key)))
+}
+
+/// Finds the newest cached token for the start url, the cache file names are hashed so every file is checked
+fn find_cached_token(cache_path: &Path, start_url: &str) -> Result<Option<CachedToken>, Error> {
+    if !cache_path.exists() {
+        return Ok(None);
+    }
+    let mut newest: Option<CachedToken> = None;
+    for entry in fs::read_dir(cache_path).with_context(|| format!("could not read {:?}", cache_path))? {
+        let file_path = entry?.path();
+        if file_path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let token = match fs::read_to_string(&file_path).map(|data| serde_json::from_str::<CachedToken>(&data)) {
+            Ok(Ok(token)) => token,
+            // Client registrations and other cache files don't hold tokens
+            _ => continue,
+        };
+        if token.start_url.as_deref().map(|url| url.trim_end_matches('/')) != Some(start_url.trim_end_matches('/')) {
+            continue;
+        }
+        let is_newer = match &newest {
+            Some(current) => parse_expires_at(&token.expires_at) > parse_expires_at(&current.expires_at),
+            None => true,
+        };
+        if is_newer {
+            newest = Some(token);
+        }
+    }
+    Ok(newest)
+}
+
+fn is_expired(token: &CachedToken) -> bool {
+    match parse_expires_at(&token.expires_at) {
+        Some(expires_at) => expires_at <= Utc::now(),
+        None => true,
+    }
+}
+
+/// Newer clients write rfc3339, older ones wrote `2019-11-14T04:05:45UTC`
+fn parse_expires_at(expires_at: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(expires_at)
+        .map(|expires_at| expires_at.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%dT%H:%M:%SUTC")
+                .ok()
+                .map(|expires_at| DateTime::<Utc>::from_utc(expires_at, Utc))
+        })
+}
+
+fn build_sso_cache_path() -> Result<PathBuf, Error> {
+    let home_dir = dirs::home_dir().ok_or_else(|| anyhow!("Missing home directory"))?;
+    Ok(Path::new(home_dir.as_path()).join(".aws/sso/cache"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use crate::test_utils::temp_path;
+
+    use super::*;
+
+    fn write_cache(name: &str, tokens: &[(&str, &str)]) -> PathBuf {
+        let cache_path = temp_path(&format!("{}-sso-cache", name));
+        fs::create_dir_all(&cache_path).unwrap();
+        for (index, (start_url, expires_at)) in tokens.iter().enumerate() {
+            let token = format!(
+                r#"{{"startUrl": "{}", "region": "eu-west-1", "accessToken": "token-{}", "expiresAt": "{}"}}"#,
+                start_url, index, expires_at
+            );
+            fs::write(cache_path.join(format!("{}.json", index)), token).unwrap();
+        }
+        fs::write(cache_path.join("botocore-client-id-eu-west-1.json"), r#"{"clientId": "id"}"#).unwrap();
+        cache_path
+    }
+
+    #[test]
+    fn test_find_cached_token() {
+        let later = (Utc::now() + Duration::hours(8)).to_rfc3339();
+        let earlier = (Utc::now() + Duration::hours(1)).to_rfc3339();
+        let cache_path = write_cache("find", &[
+            ("https://tasky.awsapps.com/start", &earlier),
+            ("https://tasky.awsapps.com/start/", &later),
+            ("https://other.awsapps.com/start", &later),
+        ]);
+        let token = find_cached_token(&cache_path, "https://tasky.awsapps.com/start").unwrap().unwrap();
+        assert_eq!(token.access_token, "token-1");
+        assert!(!is_expired(&token));
+        fs::remove_dir_all(cache_path).unwrap();
+    }
+
+    #[test]
+    fn test_find_cached_token_expired() {
+        let cache_path = write_cache("expired", &[("https://tasky.awsapps.com/start", "2019-11-14T04:05:45UTC")]);
+        let token = find_cached_token(&cache_path, "https://tasky.awsapps.com/start").unwrap().unwrap();
+        assert!(is_expired(&token));
+        assert!(find_cached_token(&cache_path, "https://other.awsapps.com/start").unwrap().is_none());
+        fs::remove_dir_all(cache_path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_expires_at() {
+        assert_eq!(parse_expires_at("2019-11-14T04:05:45UTC").unwrap().timestamp(), 1573704345);
+        assert_eq!(parse_expires_at("2019-11-14T04:05:45Z").unwrap().timestamp(), 1573704345);
+        assert!(parse_expires_at("soon").is_none());
+    }
+}
@@ -2,9 +2,13 @@ use reject::Reject;
 use serde::Serialize;
 use warp::{reject, Rejection, Reply};
 use hyper::StatusCode;
-use anyhow::{anyhow, Error};
+use anyhow::Error;
 use std::error::Error as StandardError;
 use std::convert::Infallible;
+use std::fmt;
+
+use crate::aws::secret::redact_tokens;
+use crate::origin::ForbiddenOrigin;
 
 #[derive(Serialize)]
 pub struct ErrorMessage {
@@ -19,11 +23,76 @@ pub struct ErrorWrapper {
 
 impl Reject for ErrorWrapper {}
 
+/// Credential failures the user can act on, these are returned as a 401 rather than a 500
+#[derive(Debug)]
+pub enum CredentialError {
+    MfaRequired { role_arn: String, serial_number: String },
+    MfaRejected { role_arn: String, message: String },
+    SsoSessionExpired { profile: String },
+    SessionMfaRequired { serial_number: String },
+    SessionMfaRejected { message: String },
+    ConfigLocked,
+    WrongPassphrase,
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::MfaRequired { role_arn, serial_number } => write!(
+                f,
+                "MFA is required to assume {}, provide a token_code from device {}",
+                role_arn, serial_number
+            ),
+            CredentialError::MfaRejected { role_arn, message } => write!(
+                f,
+                "MFA was rejected assuming {}: {}",
+                role_arn, message
+            ),
+            CredentialError::SsoSessionExpired { profile } => write!(
+                f,
+                "SSO session expired, run `aws sso login --profile {}`",
+                profile
+            ),
+            CredentialError::SessionMfaRequired { serial_number } => write!(
+                f,
+                "MFA is required to refresh the session, provide a token_code from device {}",
+                serial_number
+            ),
+            CredentialError::SessionMfaRejected { message } => write!(
+                f,
+                "MFA was rejected refreshing the session: {}",
+                message
+            ),
+            CredentialError::ConfigLocked => write!(
+                f,
+                "Secrets in awsManager.json are encrypted, unlock them with POST /unlock or TASKY_PASSPHRASE"
+            ),
+            CredentialError::WrongPassphrase => write!(f, "The passphrase does not decrypt awsManager.json"),
+        }
+    }
+}
+
+impl StandardError for CredentialError {}
+
+/// A request the user needs to fix, returned as a 400
+#[derive(Debug)]
+pub struct ValidationError {
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl StandardError for ValidationError {}
+
 pub fn _extract_warp_err<T>(value: Result<T, Error>) -> Result<T, Rejection> {
     match value {
         Ok(value) => Ok(value),
         Err(err) => {
-            log::error!("Failed to match result: `{}`", err);
+            log::error!("Failed to match result: `{}`", redact_tokens(&format!("{}", err)));
             Err(reject::custom(ErrorWrapper {
                 error: err
             }))
@@ -44,7 +113,8 @@ macro_rules! extract_rejection {
                 match $v {
                     Ok(value) => Ok(value),
                     Err(err) => {
-                        log::error!("Failed to match result: `{}`", err);
+                        // Aws error messages can echo keys and tokens back
+                        log::error!("Failed to match result: `{}`", $crate::aws::secret::redact_tokens(&format!("{}", err)));
                         Err(reject::custom(ErrorWrapper {
                             error: err
                         }))
@@ -61,9 +131,18 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND".to_string();
+    } else if let Some(forbidden) = err.find::<ForbiddenOrigin>() {
+        code = StatusCode::FORBIDDEN;
+        message = format!("Requests from {} can't change tasky settings, add it to TASKY_ALLOWED_ORIGINS", forbidden.origin);
+    } else if let Some(credential_error) = err.find::<ErrorWrapper>().and_then(|err| err.error.downcast_ref::<CredentialError>()) {
+        code = StatusCode::UNAUTHORIZED;
+        message = format!("{}", credential_error);
+    } else if let Some(validation_error) = err.find::<ErrorWrapper>().and_then(|err| err.error.downcast_ref::<ValidationError>()) {
+        code = StatusCode::BAD_REQUEST;
+        message = format!("{}", validation_error);
     } else if let Some(err) = err.find::<ErrorWrapper>() {
         code = StatusCode::INTERNAL_SERVER_ERROR;
-        let formatted_message = format!("{:?}", err.error);
+        let formatted_message = redact_tokens(&format!("{:?}", err.error));
         if formatted_message.contains("token") {
             let client = reqwest::Client::new();
             let result = client.post("http://localhost:3030/notify")
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::aws::cache::{CacheKey, CredentialCache};
+use crate::aws::manager::Config;
+use crate::state::AppState;
+
+/// How often the session and cached role expiries are checked
+const CHECK_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_WARNING_MINUTES: [i64; 2] = [15, 5];
+
+/// Credentials being watched for expiry
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Watched {
+    Session,
+    Role(CacheKey),
+}
+
+impl Watched {
+    fn describe(&self) -> String {
+        match self {
+            Watched::Session => "The aws session".to_owned(),
+            Watched::Role(key) => format!("The session for {} in {}", key.role_arn, key.region),
+        }
+    }
+}
+
+/// Warnings already sent for an expiry, they start over once the credentials are refreshed
+struct Sent {
+    expiration: DateTime<Utc>,
+    warned_minutes: Vec<i64>,
+    expired: bool,
+}
+
+#[derive(Default)]
+struct ExpiryWatcher {
+    sent: HashMap<Watched, Sent>,
+}
+
+impl ExpiryWatcher {
+    /// Messages for each expiry that crossed a warning threshold or expired since the last check. Only the
+    /// nearest threshold is reported when several were crossed at once, eg when the server starts.
+    fn check(&mut self, expirations: Vec<(Watched, DateTime<Utc>)>, warning_minutes: &[i64], now: DateTime<Utc>) -> Vec<String> {
+        self.sent.retain(|watched, _| expirations.iter().any(|(current, _)| current == watched));
+
+        let mut messages = Vec::new();
+        for (watched, expiration) in expirations {
+            let sent = self.sent.entry(watched.clone()).or_insert_with(|| Sent {
+                expiration,
+                warned_minutes: Vec::new(),
+                expired: false,
+            });
+            if sent.expiration != expiration {
+                *sent = Sent {
+                    expiration,
+                    warned_minutes: Vec::new(),
+                    expired: false,
+                };
+            }
+
+            let remaining = expiration - now;
+            if remaining <= Duration::zero() {
+                if !sent.expired {
+                    sent.expired = true;
+                    messages.push(format!("{} expired at {}", watched.describe(), expiration.to_rfc3339()));
+                }
+                continue;
+            }
+            let crossed: Vec<i64> = warning_minutes
+                .iter()
+                .copied()
+                .filter(|minutes| remaining <= Duration::minutes(*minutes) && !sent.warned_minutes.contains(minutes))
+                .collect();
+            if !crossed.is_empty() {
+                let minutes_left = (remaining.num_seconds() + 59) / 60;
+                messages.push(format!("{} expires in {} minutes", watched.describe(), minutes_left));
+                sent.warned_minutes.extend(crossed);
+            }
+        }
+        messages
+    }
+}
+
+/// Warns the notification subscribers before the manager session or a cached role expires, and again when it
+/// has expired
+pub async fn watch_expiry(state: AppState, cache: CredentialCache) {
+    let mut interval = tokio::time::interval(StdDuration::from_secs(CHECK_INTERVAL_SECONDS));
+    let mut watcher = ExpiryWatcher::default();
+    loop {
+        interval.tick().await;
+        // Load failures are reported by the config watcher
+        let config = match state.config() {
+            Ok(config) => config,
+            Err(_) => continue,
+        };
+        let warning_minutes = config.expiry_warning_minutes.clone().unwrap_or_else(|| DEFAULT_WARNING_MINUTES.to_vec());
+        for message in watcher.check(collect_expirations(&config, &cache), &warning_minutes, Utc::now()) {
+            info!("{}", message);
+            state.notify(message);
+        }
+    }
+}
+
+fn collect_expirations(config: &Config, cache: &CredentialCache) -> Vec<(Watched, DateTime<Utc>)> {
+    let mut expirations: Vec<(Watched, DateTime<Utc>)> = cache
+        .expirations()
+        .into_iter()
+        .map(|(key, expiration)| (Watched::Role(key), expiration))
+        .collect();
+    // Sessions read from a profile are trusted until aws rejects them, the same as `is_token_valid`
+    if config.aws_session_token.is_some() && config.aws_sts_profile.is_none() {
+        if let Some(expiration) = config.aws_session_expiration {
+            expirations.push((Watched::Session, expiration.with_timezone(&Utc)));
+        }
+    }
+    expirations
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::aws::cache::CacheSource;
+
+    use super::*;
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.ymd(2020, 9, 1).and_hms(12, 0, 0) + Duration::minutes(minute)
+    }
+
+    #[test]
+    fn test_check_thresholds() {
+        let mut watcher = ExpiryWatcher::default();
+        let expirations = || vec![(Watched::Session, at(60))];
+        let thresholds = DEFAULT_WARNING_MINUTES;
+
+        assert!(watcher.check(expirations(), &thresholds, at(30)).is_empty());
+        assert_eq!(watcher.check(expirations(), &thresholds, at(45)), vec!["The aws session expires in 15 minutes"]);
+        assert!(watcher.check(expirations(), &thresholds, at(46)).is_empty());
+        assert_eq!(watcher.check(expirations(), &thresholds, at(56)), vec!["The aws session expires in 4 minutes"]);
+        assert!(watcher.check(expirations(), &thresholds, at(58)).is_empty());
+        assert_eq!(
+            watcher.check(expirations(), &thresholds, at(60)),
+            vec!["The aws session expired at 2020-09-01T13:00:00+00:00"]
+        );
+        assert!(watcher.check(expirations(), &thresholds, at(61)).is_empty());
+    }
+
+    #[test]
+    fn test_check_crossed_together() {
+        let mut watcher = ExpiryWatcher::default();
+        let key = CacheKey {
+            role_arn: "arn:aws:iam::123456789012:role/tasky".to_owned(),
+            region: "eu-west-1".to_owned(),
+            role_session_name: "tasky".to_owned(),
+            duration_seconds: None,
+            external_id: None,
+            policy: None,
+            source: CacheSource::Manager,
+            workspace: None,
+        };
+        let messages = watcher.check(vec![(Watched::Role(key.clone()), at(3))], &DEFAULT_WARNING_MINUTES, at(0));
+        assert_eq!(messages, vec!["The session for arn:aws:iam::123456789012:role/tasky in eu-west-1 expires in 3 minutes"]);
+        assert!(watcher.check(vec![(Watched::Role(key), at(3))], &DEFAULT_WARNING_MINUTES, at(1)).is_empty());
+    }
+
+    #[test]
+    fn test_check_refreshed() {
+        let mut watcher = ExpiryWatcher::default();
+        assert_eq!(watcher.check(vec![(Watched::Session, at(10))], &DEFAULT_WARNING_MINUTES, at(0)).len(), 1);
+        // A refreshed session warns again as it gets close to its new expiry
+        assert!(watcher.check(vec![(Watched::Session, at(70))], &DEFAULT_WARNING_MINUTES, at(1)).is_empty());
+        assert_eq!(watcher.check(vec![(Watched::Session, at(70))], &DEFAULT_WARNING_MINUTES, at(56)).len(), 1);
+    }
+}
@@ -1,5 +1,7 @@
 #![feature(try_trait)]
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
@@ -11,27 +13,58 @@ use std::sync::Mutex;
 use warp::{Filter};
 use warp::hyper::Method;
 
-use aws::ecs::get_ecs_filter;
+use aws::ecs::{get_ecs_accounts_filter, get_ecs_filter};
 
+use crate::aws::cache::CredentialCache;
 use crate::aws::cloudwatch_logs::{get_logs_events_filter, get_logs_filter};
 use crate::aws::cloudwatch_logs::dto::LogsOptions;
-use crate::aws::dto::AwsRequest;
-use crate::aws::manager::setup_default_manager;
+use crate::aws::dto::{AccountsRequest, AwsRequest, ConfigUpdate, IdentityRequest, PermissionsRequest, SessionRefreshRequest, UnlockRequest};
+use crate::aws::encryption::{unlock_filter, Keyring};
+use crate::aws::identity::get_identity_filter;
+use crate::aws::manager::{get_config_filter, parse_config_path, patch_config_filter, put_config_filter, reset_config_filter, set_config_path, setup_default_manager};
+use crate::aws::permissions::get_permissions_filter;
+use crate::aws::profile::get_profiles_filter;
+use crate::aws::roles::{create_role_filter, delete_role_filter, get_role_filter, list_roles_filter, update_role_filter, NamedRole};
+use crate::aws::secret::redact_tokens;
+use crate::aws::session::{get_session_filter, refresh_session_filter};
 use error::handle_rejection;
+use crate::expiry::watch_expiry;
 use crate::notifications::{subscriber_connected, build_fan_notifications, NotUtf8};
+use crate::origin::{parse_allowed_origins, trusted_origin};
+use crate::state::{watch_config, AppState};
 
 mod aws;
 mod error;
+mod expiry;
 mod notifications;
+mod origin;
+mod state;
+#[cfg(test)]
+mod test_utils;
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
+    // Taken out of the environment so credential_process commands don't inherit it
+    let passphrase = std::env::var("TASKY_PASSPHRASE").ok();
+    std::env::remove_var("TASKY_PASSPHRASE");
+    let keyring = Keyring::new(passphrase);
+
+    set_config_path(parse_config_path(std::env::args().skip(1), std::env::var_os("TASKY_CONFIG")));
 
     let subscribers = Arc::new(Mutex::new(HashMap::new()));
+
+    let app_state = AppState::new(subscribers.clone(), keyring);
+    tokio::spawn(watch_config(app_state.clone()));
+
     let subscribers = warp::any().map(move || subscribers.clone());
 
+    let credential_cache = CredentialCache::new();
+    tokio::spawn(watch_expiry(app_state.clone(), credential_cache.clone()));
+    let app_state = warp::any().map(move || app_state.clone());
+    let credential_cache = warp::any().map(move || credential_cache.clone());
+
     let cors_headers = vec![
         "User-Agent",
         "Sec-Fetch-Mode",
@@ -44,32 +77,167 @@ async fn main() {
         "role_arn"
     ];
 
+    // Anything can read when no gui origin is configured, the routes changing settings check the origin themselves
+    let allowed_origins = parse_allowed_origins(std::env::var("TASKY_ALLOWED_ORIGINS").ok());
     let cors = warp::cors()
-        .allow_any_origin()
         .allow_headers(cors_headers)
-        .allow_methods(&[Method::GET, Method::POST]);
+        .allow_methods(&[Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE]);
+    let cors = match &allowed_origins {
+        Some(allowed_origins) => cors.allow_origins(allowed_origins.iter().map(String::as_str)),
+        None => cors.allow_any_origin(),
+    };
+    let trusted_origin = trusted_origin(allowed_origins);
+
+    // Only the path is logged, query strings and bodies can hold tokens
+    let request_log = warp::log::custom(|info| {
+        info!("{} {} {} in {:?}", info.method(), redact_tokens(info.path()), info.status(), info.elapsed());
+    });
 
     let ecs = warp::path("ecs")
         .and(warp::post())
         .and(warp::body::content_length_limit(1024 * 16))
         .and(warp::body::json::<AwsRequest>())
+        .and(credential_cache.clone())
+        .and(app_state.clone())
         .and_then(get_ecs_filter);
 
+    let ecs_accounts = warp::path("ecs")
+        .and(warp::path("accounts"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<AccountsRequest>())
+        .and(credential_cache.clone())
+        .and(app_state.clone())
+        .and_then(get_ecs_accounts_filter);
+
     let log_stream = warp::path("logs")
         .and(warp::path("events"))
         .and(warp::get())
         .and(warp::query::<LogsOptions>())
+        .and(credential_cache.clone())
+        .and(app_state.clone())
         .and_then(get_logs_events_filter);
 
     let logs = warp::path("logs")
         .and(warp::get())
         .and(warp::query::<LogsOptions>())
+        .and(credential_cache.clone())
+        .and(app_state.clone())
         .and_then(get_logs_filter);
 
+    let identity = warp::path("identity")
+        .and(warp::get())
+        .and(warp::query::<IdentityRequest>())
+        .and(credential_cache.clone())
+        .and(app_state.clone())
+        .and_then(get_identity_filter);
+
+    let permissions = warp::path("permissions")
+        .and(warp::get())
+        .and(warp::query::<PermissionsRequest>())
+        .and(credential_cache)
+        .and(app_state.clone())
+        .and_then(get_permissions_filter);
+
     let bootstrap_config = warp::path("config")
+        .and(warp::path::end())
         .and(warp::post())
+        .and(trusted_origin.clone())
+        .and(app_state.clone())
         .and_then(setup_default_manager);
 
+    let get_config = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(app_state.clone())
+        .and_then(get_config_filter);
+
+    let put_config = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<ConfigUpdate>())
+        .and(app_state.clone())
+        .and_then(put_config_filter);
+
+    let patch_config = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<ConfigUpdate>())
+        .and(app_state.clone())
+        .and_then(patch_config_filter);
+
+    let reset_config = warp::path("config")
+        .and(warp::path("reset"))
+        .and(warp::post())
+        .and(trusted_origin.clone())
+        .and(app_state.clone())
+        .and_then(reset_config_filter);
+
+    let unlock = warp::path("unlock")
+        .and(warp::post())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<UnlockRequest>())
+        .and(app_state.clone())
+        .and_then(unlock_filter);
+
+    let list_roles = warp::path("roles")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(app_state.clone())
+        .and_then(list_roles_filter);
+
+    let get_role = warp::path!("roles" / String)
+        .and(warp::get())
+        .and(app_state.clone())
+        .and_then(get_role_filter);
+
+    let create_role = warp::path("roles")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<NamedRole>())
+        .and(app_state.clone())
+        .and_then(create_role_filter);
+
+    let update_role = warp::path!("roles" / String)
+        .and(warp::put())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<NamedRole>())
+        .and(app_state.clone())
+        .and_then(update_role_filter);
+
+    let delete_role = warp::path!("roles" / String)
+        .and(warp::delete())
+        .and(trusted_origin.clone())
+        .and(app_state.clone())
+        .and_then(delete_role_filter);
+
+    let profiles = warp::path("profiles")
+        .and(warp::get())
+        .and_then(get_profiles_filter);
+
+    let session = warp::path("session")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(app_state.clone())
+        .and_then(get_session_filter);
+
+    let refresh_session = warp::path("session")
+        .and(warp::path("refresh"))
+        .and(warp::post())
+        .and(trusted_origin.clone())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<SessionRefreshRequest>())
+        .and(app_state)
+        .and_then(refresh_session_filter);
+
     let notify = warp::path("notify")
         .and(warp::post())
         .and(warp::body::content_length_limit(500))
@@ -97,12 +265,29 @@ async fn main() {
         });
 
     warp::serve(
-        ecs.or(logs)
+        ecs_accounts.or(ecs)
+            .or(logs)
             .or(log_stream)
+            .or(identity)
+            .or(permissions)
             .or(bootstrap_config)
+            .or(get_config)
+            .or(put_config)
+            .or(patch_config)
+            .or(reset_config)
+            .or(unlock)
+            .or(list_roles)
+            .or(get_role)
+            .or(create_role)
+            .or(update_role)
+            .or(delete_role)
+            .or(profiles)
+            .or(session)
+            .or(refresh_session)
             .or(notify)
             .or(notifications)
             .with(cors)
+            .with(request_log)
             .recover(handle_rejection)
     )
         .run(([127, 0, 0, 1], 3030)).await;
@@ -27,7 +27,7 @@ pub type Subscribers = Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Notificati
 
 pub fn subscriber_connected(
     subscribers: Subscribers,
-) -> impl Stream<Item=Result<impl ServerSentEvent + Send + 'static, warp::Error>> + Send + 'static
+) -> impl Stream<Item=Result<impl ServerSentEvent + 'static, warp::Error>> + Send + 'static
 {
     // Use a counter to assign a new unique ID for this user.
     let new_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
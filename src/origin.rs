@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use warp::reject::{self, Reject};
+use warp::{Filter, Rejection};
+
+const LOCAL_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// A browser request to change settings from a page that isn't the gui, returned as a 403
+#[derive(Debug)]
+pub struct ForbiddenOrigin {
+    pub origin: String,
+}
+
+impl Reject for ForbiddenOrigin {}
+
+/// Origins the gui is served from, from TASKY_ALLOWED_ORIGINS as a comma separated list like
+/// `http://localhost:3000,https://tasky.example.com`
+pub fn parse_allowed_origins(value: Option<String>) -> Option<Vec<String>> {
+    let origins: Vec<String> = value?
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+        .filter(|origin| !origin.is_empty())
+        .filter(|origin| {
+            let is_valid = split_origin(origin).is_some();
+            if !is_valid {
+                warn!("Ignoring `{}` in TASKY_ALLOWED_ORIGINS, origins look like http://localhost:3000", origin);
+            }
+            is_valid
+        })
+        .collect();
+    if origins.is_empty() {
+        None
+    } else {
+        Some(origins)
+    }
+}
+
+/// Rejects browser requests from origins that aren't allowed, only the configured origins when there are some and
+/// otherwise pages served from this machine. Requests without an Origin, eg from curl, don't come from a page and
+/// are let through.
+pub fn trusted_origin(allowed_origins: Option<Vec<String>>) -> impl Filter<Extract=(), Error=Rejection> + Clone {
+    let allowed_origins = Arc::new(allowed_origins);
+    warp::header::optional::<String>("origin")
+        .and_then(move |origin: Option<String>| {
+            let allowed_origins = allowed_origins.clone();
+            async move {
+                match origin {
+                    Some(origin) if !is_allowed_origin(&origin, &allowed_origins) => {
+                        warn!("Refusing a request from origin {}", origin);
+                        Err(reject::custom(ForbiddenOrigin { origin }))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn is_allowed_origin(origin: &str, allowed_origins: &Option<Vec<String>>) -> bool {
+    let origin = origin.trim_end_matches('/');
+    match allowed_origins {
+        Some(allowed_origins) => allowed_origins.iter().any(|allowed| allowed == origin),
+        None => split_origin(origin)
+            .map(|host| LOCAL_HOSTS.contains(&host))
+            .unwrap_or(false),
+    }
+}
+
+/// The host of an origin like `http://localhost:3000`, or none when it isn't a scheme, host and optional port
+fn split_origin(origin: &str) -> Option<&str> {
+    let rest = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))?;
+    let (host, port) = match rest.find(']') {
+        Some(end) if rest.starts_with('[') => (&rest[..=end], &rest[end + 1..]),
+        _ => match rest.find(':') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, ""),
+        },
+    };
+    let is_valid_port = port.is_empty()
+        || (port.len() > 1 && port.starts_with(':') && port[1..].chars().all(|c| c.is_ascii_digit()));
+    let is_valid_host = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || ".-[]:".contains(c));
+    if is_valid_port && is_valid_host {
+        Some(host)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(
+            parse_allowed_origins(Some(" http://localhost:3000/, https://tasky.example.com,file:///gui,http://tasky gui".to_owned())),
+            Some(vec!["http://localhost:3000".to_owned(), "https://tasky.example.com".to_owned()])
+        );
+        assert_eq!(parse_allowed_origins(Some(" , ".to_owned())), None);
+        assert_eq!(parse_allowed_origins(None), None);
+    }
+
+    #[test]
+    fn test_is_allowed_origin() {
+        assert!(is_allowed_origin("http://localhost:3000", &None));
+        assert!(is_allowed_origin("http://127.0.0.1", &None));
+        assert!(is_allowed_origin("http://[::1]:8080", &None));
+        assert!(!is_allowed_origin("https://evil.example.com", &None));
+        assert!(!is_allowed_origin("http://localhost.evil.example.com", &None));
+        assert!(!is_allowed_origin("null", &None));
+
+        let allowed_origins = Some(vec!["https://tasky.example.com".to_owned()]);
+        assert!(is_allowed_origin("https://tasky.example.com", &allowed_origins));
+        assert!(!is_allowed_origin("http://localhost:3000", &allowed_origins));
+    }
+
+    #[tokio::test]
+    async fn test_trusted_origin() {
+        let filter = trusted_origin(None);
+        assert!(warp::test::request().filter(&filter).await.is_ok());
+        assert!(warp::test::request().header("origin", "http://localhost:3000").filter(&filter).await.is_ok());
+        assert!(warp::test::request().header("origin", "https://evil.example.com").filter(&filter).await.is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Error;
+
+use crate::aws::encryption::Keyring;
+use crate::aws::manager::{build_watched_paths, Config};
+use crate::aws::secret::redact_tokens;
+use crate::notifications::{build_fan_notifications, Subscribers};
+
+/// How often the watcher checks the config and credentials files for changes
+const WATCH_INTERVAL_SECONDS: u64 = 2;
+
+type Loader = Arc<dyn Fn() -> Result<Config, Error> + Send + Sync>;
+type Saver = Arc<dyn Fn(&Config) -> Result<(), Error> + Send + Sync>;
+type Paths = Arc<dyn Fn() -> Vec<PathBuf> + Send + Sync>;
+
+/// Modified time and length of each watched file, None when the file is missing
+type Fingerprint = Vec<Option<(SystemTime, u64)>>;
+
+struct Loaded {
+    config: Arc<Config>,
+    fingerprint: Fingerprint,
+}
+
+/// Shared server state injected into the warp handlers. The manager config is loaded once and loaded again
+/// when the manager config file or the aws credentials file change.
+#[derive(Clone)]
+pub struct AppState {
+    loaded: Arc<RwLock<Option<Loaded>>>,
+    subscribers: Subscribers,
+    keyring: Keyring,
+    loader: Loader,
+    saver: Saver,
+    paths: Paths,
+}
+
+impl AppState {
+    pub fn new(subscribers: Subscribers, keyring: Keyring) -> AppState {
+        let loader_keyring = keyring.clone();
+        let saver_keyring = keyring.clone();
+        AppState::with_loader(
+            subscribers,
+            keyring,
+            Arc::new(move || Config::load(&loader_keyring)),
+            Arc::new(move |config: &Config| config.persist(&saver_keyring)),
+            Arc::new(build_watched_paths),
+        )
+    }
+
+    fn with_loader(subscribers: Subscribers, keyring: Keyring, loader: Loader, saver: Saver, paths: Paths) -> AppState {
+        AppState {
+            loaded: Arc::new(RwLock::new(None)),
+            subscribers,
+            keyring,
+            loader,
+            saver,
+            paths,
+        }
+    }
+
+    /// The current config, loading it first when it hasn't loaded yet or the files changed
+    pub fn config(&self) -> Result<Arc<Config>, Error> {
+        let fingerprint = self.fingerprint();
+        if let Some(loaded) = self.loaded.read().unwrap().as_ref() {
+            if loaded.fingerprint == fingerprint {
+                return Ok(loaded.config.clone());
+            }
+        }
+        self.reload()
+    }
+
+    /// Loads the config again, eg after unlocking it
+    pub fn reload(&self) -> Result<Arc<Config>, Error> {
+        let mut loaded = self.loaded.write().unwrap();
+        self.reload_locked(&mut loaded)
+    }
+
+    /// Changes the config and persists it. The lock is held from loading the file through to reloading it, so
+    /// changes made at the same time are applied one after the other rather than overwriting each other.
+    pub fn update<T, F>(&self, change: F) -> Result<(Arc<Config>, T), Error>
+        where F: FnOnce(&mut Config) -> Result<T, Error>, {
+        let mut loaded = self.loaded.write().unwrap();
+        // Changes start from the file rather than the loaded config, which may be older
+        let mut config = (self.loader)()?;
+        let result = change(&mut config)?;
+        (self.saver)(&config)?;
+        Ok((self.reload_locked(&mut loaded)?, result))
+    }
+
+    fn reload_locked(&self, loaded: &mut Option<Loaded>) -> Result<Arc<Config>, Error> {
+        let config = Arc::new((self.loader)()?);
+        // Loading can write the file, so the fingerprint is taken afterwards
+        let fingerprint = self.fingerprint();
+
+        if let Some(previous) = loaded.as_ref() {
+            if credentials_changed(&previous.config, &config) {
+                info!("Aws credentials changed, config reloaded");
+                self.notify("Aws credentials changed".to_owned());
+            }
+        }
+        *loaded = Some(Loaded {
+            config: config.clone(),
+            fingerprint,
+        });
+        Ok(config)
+    }
+
+    /// The passphrase the config is loaded and persisted with
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Sends the message to every notification subscriber
+    pub fn notify(&self, message: String) {
+        build_fan_notifications(message, &self.subscribers);
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        (self.paths)()
+            .iter()
+            .map(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Polls the watched files so subscribers hear about changed credentials without waiting for a request
+pub async fn watch_config(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(WATCH_INTERVAL_SECONDS));
+    let mut last_error: Option<String> = None;
+    loop {
+        interval.tick().await;
+        match state.config() {
+            Ok(_) => last_error = None,
+            Err(err) => {
+                let message = redact_tokens(&format!("Error reloading the aws manager config: {}", err));
+                // Only tell subscribers once rather than on every tick
+                if last_error.as_ref() != Some(&message) {
+                    error!("{}", message);
+                    state.notify(message.clone());
+                    last_error = Some(message);
+                }
+            }
+        }
+    }
+}
+
+fn credentials_changed(previous: &Config, current: &Config) -> bool {
+    previous.aws_access_key_id != current.aws_access_key_id
+        || previous.aws_secret_access_key != current.aws_secret_access_key
+        || previous.aws_temp_access_key_id != current.aws_temp_access_key_id
+        || previous.aws_temp_secret_access_key != current.aws_temp_secret_access_key
+        || previous.aws_session_token != current.aws_session_token
+        || previous.aws_session_expiration != current.aws_session_expiration
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::mpsc;
+
+    use crate::notifications::Notification;
+    use crate::test_utils::write_temp_file;
+
+    use super::*;
+
+    fn build_state(file_path: PathBuf, loads: Arc<AtomicUsize>) -> AppState {
+        let loader_path = file_path.clone();
+        let loader: Loader = Arc::new(move || {
+            loads.fetch_add(1, Ordering::Relaxed);
+            Ok(Config {
+                aws_session_token: Some(fs::read_to_string(&loader_path)?.into()),
+                ..Default::default()
+            })
+        });
+        let saver_path = file_path.clone();
+        let saver: Saver = Arc::new(move |config: &Config| {
+            let token = config.aws_session_token.as_ref().map(|token| token.expose().to_owned()).unwrap_or_default();
+            Ok(fs::write(&saver_path, token)?)
+        });
+        let paths: Paths = Arc::new(move || vec![file_path.clone()]);
+        AppState::with_loader(Arc::new(Mutex::new(HashMap::new())), Keyring::default(), loader, saver, paths)
+    }
+
+    #[test]
+    fn test_config_reloads_on_change() {
+        let file_path = write_temp_file("state", "token");
+        let loads = Arc::new(AtomicUsize::new(0));
+        let state = build_state(file_path.clone(), loads.clone());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.subscribers.lock().unwrap().insert(1, tx);
+
+        assert_eq!(state.config().unwrap().aws_session_token, Some("token".into()));
+        state.config().unwrap();
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert!(rx.try_recv().is_err());
+
+        fs::write(&file_path, "new-token").unwrap();
+        assert_eq!(state.config().unwrap().aws_session_token, Some("new-token".into()));
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+        match rx.try_recv() {
+            Ok(Notification::Message(message)) => assert!(message.contains("credentials changed")),
+            other => panic!("Expected a notification, got {:?}", other),
+        }
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_update_serialises_changes() {
+        let file_path = write_temp_file("state-update", "");
+        let state = build_state(file_path.clone(), Arc::new(AtomicUsize::new(0)));
+        state.config().unwrap();
+
+        let writers: Vec<std::thread::JoinHandle<()>> = (0..8)
+            .map(|writer| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    state.update(|config| {
+                        let token = config.aws_session_token.as_ref().map(|token| token.expose().to_owned()).unwrap_or_default();
+                        config.aws_session_token = Some(format!("{}{}", token, writer).into());
+                        Ok(())
+                    }).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // Every writer's change is kept, none started from a config another writer was still changing
+        let token = fs::read_to_string(&file_path).unwrap();
+        let mut writes: Vec<char> = token.chars().collect();
+        writes.sort();
+        assert_eq!(writes, vec!['0', '1', '2', '3', '4', '5', '6', '7']);
+        assert_eq!(state.config().unwrap().aws_session_token, Some(token.into()));
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_update_fails_without_saving() {
+        let file_path = write_temp_file("state-update-fail", "token");
+        let state = build_state(file_path.clone(), Arc::new(AtomicUsize::new(0)));
+        let result: Result<(Arc<Config>, ()), Error> = state.update(|config| {
+            config.aws_session_token = Some("changed".into());
+            Err(anyhow::anyhow!("Invalid change"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "token");
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_credentials_changed() {
+        let previous = Config {
+            aws_session_token: Some("token".into()),
+            region: Some("eu-west-1".to_owned()),
+            ..Default::default()
+        };
+        let region_only = Config {
+            aws_session_token: Some("token".into()),
+            ..Default::default()
+        };
+        assert!(!credentials_changed(&previous, &region_only));
+        assert!(credentials_changed(&previous, &Config::default()));
+    }
+}
@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// A path in the temp directory that no other test uses, the process id keeps concurrent test runs apart
+pub fn temp_path(name: &str) -> PathBuf {
+    let file_id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("tasky-{}-{}-{}", std::process::id(), file_id, name))
+}
+
+/// Writes the contents to a new temp file
+pub fn write_temp_file(name: &str, contents: &str) -> PathBuf {
+    let file_path = temp_path(name);
+    std::fs::write(&file_path, contents).unwrap();
+    file_path
+}